@@ -1,6 +1,3 @@
-LOADW r0 0x6F73 ; 'os'
-LOADW r1 0x6563 ; 'ec'
-LOADW r2 0x2E69 ; '.i'
-LOADW r3 0x6F00 ; 'o\0'
-FLAG
-HLT
\ No newline at end of file
+        LOADSTR r0 "osec.io"    ; r0-r3 = 'os' 'ec' '.i' 'o\0'
+        FLAG
+        HLT
@@ -0,0 +1,24 @@
+; Payload written over the checker by the circuit built in src/main.rs
+        LOADW r7 0xf000
+        LOADW r6 0xf000
+        LOADW r0 0x6F73         ; 'os'
+        LOADW r1 0x6563         ; 'ec'
+        LOADW r2 0x2E69         ; '.i'
+        LOADW r3 0x6F00         ; 'o\0'
+        ADD r7 r7
+        ADD r7 r7
+        ADD r7 r7
+        ADD r7 r6
+
+        ADD r0 r7
+        ADD r1 r7
+        ADD r2 r7
+        ADD r3 r7
+
+        ADD r6 r6
+        ADD r6 r6
+
+        ADD r2 r6
+        FLAG
+
+        HLT
@@ -1,11 +1,154 @@
-use crate::err;
-use std::error::Error;
+use crate::error::{AsmError, AsmErrors};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-type LabelMap = HashMap<String, usize>;
+mod ast;
+mod builder;
+mod cfg;
+mod conditional;
+mod data;
+mod expr;
+mod format;
+mod include;
+mod link;
+mod lint;
+mod listing;
+mod macros;
+mod names;
+mod object;
+mod optimize;
+mod output;
+mod pseudo;
+mod structured;
 
-#[derive(Debug, Clone)]
+pub use ast::{parse, parse_named, Operand, OperandKind, Program, Span, Statement, StatementKind, Word};
+pub use builder::{Label, ProgramBuilder, Value};
+pub use cfg::{Block, Cfg, CodeInstruction, Edge, EdgeKind, Loop, Transfer, CYCLES_PER_INSTRUCTION};
+pub use format::{format, format_program};
+use link::KeyedErrors;
+pub use link::{link, Layout, LayoutEntry};
+pub use lint::{lint, Diagnostic, Lint, LintConfig, Region, Severity};
+pub use listing::{Assembly, ListingLine, Symbol, SymbolKind};
+pub use object::{Object, Section};
+pub use optimize::{optimize, Optimization, Rewrite};
+pub use output::{OutputFormat, Segment};
+
+/// Labels and `.equ` constants, by name.
+type SymbolMap = HashMap<String, i64>;
+
+/// A single line of assembly source, remembered so diagnostics can point at it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SourceLine {
+    #[serde(with = "rc_str")]
+    file: Rc<str>,
+    number: usize,
+    text: String,
+    /// Call sites this line was expanded from, attached to its diagnostics
+    notes: Vec<AsmError>,
+    /// Local and numeric labels and register aliases on this line, with the
+    /// names they stand for
+    #[serde(default)]
+    aliases: Vec<(String, String)>,
+}
+
+/// Source lines share their file name, which serde only handles as a `String`.
+mod rc_str {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::rc::Rc;
+
+    pub fn serialize<S: Serializer>(value: &Rc<str>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(value)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Rc<str>, D::Error> {
+        String::deserialize(deserializer).map(Rc::from)
+    }
+}
+
+/// A whitespace-separated word on a source line, with its 0-based column.
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    column: usize,
+}
+
+impl SourceLine {
+    fn new(file: &Rc<str>, number: usize, text: &str) -> Self {
+        SourceLine { file: file.clone(), number, text: text.to_string(), notes: Vec::new(), aliases: Vec::new() }
+    }
+
+    /// The line without its comment, which starts with `;` or `#` outside
+    /// of string and character literals.
+    fn code(&self) -> &str {
+        let mut pos = 0;
+        while pos < self.text.len() {
+            let rest = &self.text[pos..];
+            if rest.starts_with(';') || rest.starts_with('#') {
+                return &self.text[..pos];
+            }
+            if rest.starts_with('"') || rest.starts_with('\'') {
+                pos += data::quoted_len(rest).unwrap_or(rest.len());
+            } else {
+                pos += rest.chars().next().unwrap().len_utf8();
+            }
+        }
+        &self.text
+    }
+
+    /// The name a symbol or register written on this line stands for.
+    fn resolve<'a>(&'a self, name: &'a str) -> &'a str {
+        match self.aliases.iter().find(|(written, _)| written == name) {
+            Some((_, stored)) => stored,
+            None => name,
+        }
+    }
+
+    /// The words of the line, separated by whitespace or commas outside of
+    /// string and character literals.
+    fn tokens(&self) -> Vec<Token<'_>> {
+        let code = self.code();
+        let mut tokens = Vec::new();
+        let mut start = None;
+        let mut pos = 0;
+        while pos < code.len() {
+            let rest = &code[pos..];
+            let c = rest.chars().next().unwrap();
+            if c.is_whitespace() || c == ',' {
+                if let Some(s) = start.take() {
+                    tokens.push(Token { text: &code[s..pos], column: s });
+                }
+                pos += c.len_utf8();
+                continue;
+            }
+            start.get_or_insert(pos);
+            pos += match c {
+                '"' | '\'' => data::quoted_len(rest).unwrap_or(rest.len()),
+                _ => c.len_utf8(),
+            };
+        }
+        if let Some(s) = start {
+            tokens.push(Token { text: &code[s..], column: s });
+        }
+        tokens
+    }
+
+    fn error_at(&self, column: usize, len: usize, message: impl Into<String>) -> AsmError {
+        let mut error = AsmError::new(&self.file, self.number, column + 1, len, &self.text, message);
+        error.notes = self.notes.clone();
+        error
+    }
+
+    fn error(&self, token: &Token, message: impl Into<String>) -> AsmError {
+        self.error_at(token.column, token.text.len(), message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Instruction {
     Nop,
     Add { dest: u8, src: u8 },
@@ -21,114 +164,326 @@ pub enum Instruction {
     Invalid,
 }
 
-fn parse_register(reg_str: &str) -> Result<u8, Box<dyn Error>> {
-    let reg_str = reg_str.to_uppercase();
-    assert!(reg_str.starts_with('R'), "Register must start with 'r' or 'R': '{}'", reg_str);
-    let num_str = &reg_str[1..];
-    let reg_num = num_str.parse::<u8>()?;
-    assert!(reg_num < 8, "Register index must be 0-15: '{}'", reg_str);
-    Ok(reg_num)
+impl Instruction {
+    /// Size of the encoded instruction in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::LoadW { .. } => 4,
+            _ => 2,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Nop => write!(f, "NOP"),
+            Instruction::Add { dest, src } => write!(f, "ADD r{} r{}", dest, src),
+            Instruction::AddI { dest, imm } => write!(f, "ADDI r{} {}", dest, imm),
+            Instruction::Nand { dest, src } => write!(f, "NAND r{} r{}", dest, src),
+            Instruction::LoadI { dest, imm } => write!(f, "LOADI r{} {}", dest, imm),
+            Instruction::Store { addr, src } => write!(f, "STORE r{} r{}", addr, src),
+            Instruction::Load { dest, src } => write!(f, "LOAD r{} r{}", dest, src),
+            Instruction::Jz { reg, addr } => write!(f, "JZ r{} {}", reg, addr),
+            Instruction::LoadW { dest, imm } => write!(f, "LOADW r{} 0x{:04X}", dest, imm),
+            Instruction::Gt { dest, src1, src2 } => write!(f, "GT r{} r{} r{}", dest, src1, src2),
+            Instruction::Flag => write!(f, "FLAG"),
+            Instruction::Invalid => write!(f, "HLT"),
+        }
+    }
+}
+
+fn parse_register(token: &Token, line: &SourceLine) -> Result<u8, AsmError> {
+    let reg_str = line.resolve(token.text).to_uppercase();
+    if !reg_str.starts_with('R') {
+        return Err(line.error(token, format!("expected a register (r0-r7), found `{}`", token.text)));
+    }
+    match reg_str[1..].parse::<u8>() {
+        Ok(reg_num) if reg_num < 8 => Ok(reg_num),
+        Ok(_) => Err(line.error(token, format!("register index out of range: `{}` (expected r0-r7)", token.text))),
+        Err(_) => Err(line.error(token, format!("invalid register `{}` (expected r0-r7)", token.text))),
+    }
+}
+
+/// A decimal, `0x` hex or `0b` binary literal, with optional `_` between digits.
+fn parse_number(num_str: &str) -> Option<i64> {
+    let (digits, radix) = match num_str.get(..2) {
+        Some("0x") => (&num_str[2..], 16),
+        Some("0b") => (&num_str[2..], 2),
+        _ => (num_str, 10),
+    };
+    if !digits.starts_with(|c: char| c.is_ascii_alphanumeric()) || digits.ends_with('_') {
+        return None;
+    }
+    i64::from_str_radix(&digits.replace('_', ""), radix).ok()
+}
+
+/// `.data` used to read its addresses and values as hex without a `0x`
+/// prefix. A bare number that means something else in hex is rejected
+/// rather than silently changing value.
+fn check_unprefixed_hex(token: &Token, line: &SourceLine) -> Result<(), AsmError> {
+    let text = token.text;
+    let hex = match u64::from_str_radix(text, 16) {
+        Ok(hex) if !text.starts_with("0b") => hex,
+        _ => return Ok(()),
+    };
+    if text.parse::<u64>() == Ok(hex) {
+        return Ok(());
+    }
+    let mut message = format!("`{}` is ambiguous: `.data` used to read bare numbers as hex; write `0x{}` for hex", text, text);
+    if text.parse::<u64>().is_ok() {
+        message.push_str(&format!(", or `.word {}` for decimal", text));
+    }
+    Err(line.error(token, message))
+}
+
+/// A token spanning operands `from..` of an instruction, used for expressions
+/// that may contain whitespace.
+fn operand_span<'a>(parts: &[Token<'a>], from: usize, line: &'a SourceLine) -> Token<'a> {
+    let first = &parts[from];
+    let last = &parts[parts.len() - 1];
+    Token {
+        text: &line.text[first.column..last.column + last.text.len()],
+        column: first.column,
+    }
+}
+
+fn parse_immediate(token: &Token, symbols: &SymbolMap, line: &SourceLine) -> Result<u8, AsmError> {
+    let value = expr::evaluate(token, symbols, line)?;
+    // The CPU zero-extends the field, so a negative value would not act like one
+    if value < 0 {
+        return Err(line.error(token, format!(
+            "`{}` evaluates to {}, but the 8-bit immediate field is zero-extended and cannot hold negative values \
+             (use `SUBI` to subtract a constant, or `LOADW` to load a negative one)",
+            token.text, value
+        )));
+    }
+    u8::try_from(value).map_err(|_| line.error(token, format!(
+        "`{}` evaluates to {}, which does not fit in the 8-bit immediate field", token.text, value
+    )))
+}
+
+/// Resolve a JZ target. The CPU loads the 8-bit immediate straight into the
+/// program counter, so no instruction sequence (trampolines or a patched JZ
+/// included) can branch above 0xff; code there is only reachable by falling
+/// through, and labels past that point get a dedicated diagnostic.
+fn parse_jump_target(token: &Token, symbols: &SymbolMap, line: &SourceLine) -> Result<u8, AsmError> {
+    let target = expr::evaluate(token, symbols, line)?;
+    if target < 0 {
+        return Err(line.error(token, format!("jump target `{}` evaluates to {}, which is negative", token.text, target)));
+    }
+
+    u8::try_from(target).map_err(|_| line.error(token, format!(
+        "jump target `{}` is at address 0x{:x}, but JZ can only reach 0x00-0xff \
+         (move the target code below 0x100; there is no far-jump form in this ISA)",
+        token.text, target
+    )))
+}
+
+fn parse_wide_immediate(token: &Token, symbols: &SymbolMap, line: &SourceLine) -> Result<u16, AsmError> {
+    // Negative values are stored as two's complement
+    let value = expr::evaluate(token, symbols, line)?;
+    if !(-0x8000..=0xFFFF).contains(&value) {
+        return Err(line.error(token, format!("`{}` evaluates to {}, which does not fit in 16 bits", token.text, value)));
+    }
+    Ok(value as u16)
+}
+
+fn parse_data_address(token: &Token, line: &SourceLine) -> Result<usize, AsmError> {
+    check_unprefixed_hex(token, line)?;
+    let addr = match parse_number(token.text) {
+        Some(addr) if (0..=0xFFFF).contains(&addr) => addr as usize,
+        Some(addr) => return Err(line.error(token, format!("data address {} is outside memory", addr))),
+        None => return Err(line.error(token, format!("invalid address `{}`", token.text))),
+    };
+    if addr % 2 != 0 {
+        return Err(line.error(token, "data must be aligned to 2-byte boundaries"));
+    }
+    Ok(addr)
+}
+
+fn parse_data_value(token: &Token, symbols: &SymbolMap, line: &SourceLine) -> Result<u16, AsmError> {
+    // A lone symbol is never mistaken for a hex word
+    if !symbols.contains_key(line.resolve(token.text)) {
+        check_unprefixed_hex(token, line)?;
+    }
+    parse_wide_immediate(token, symbols, line)
+}
+
+fn is_symbol_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
 }
 
-fn parse_immediate(imm_str: &str, labels: &LabelMap) -> Result<u8, Box<dyn Error>> {
-    // First check if it's a label
-    if let Some(&addr) = labels.get(imm_str) {
-        // Convert byte address to instruction count (each instruction is 2 bytes)
-        // The jump target should be addr/2 since each instruction is 2 bytes
-        return Ok(u8::try_from(addr).unwrap());
+/// Returns the label name if the line is just a label definition.
+fn label_definition<'a>(parts: &[Token<'a>]) -> Option<&'a str> {
+    match parts {
+        [token] if token.text.ends_with(':') => Some(token.text.trim_end_matches(':')),
+        _ => None,
     }
-    
-    // Otherwise parse as number
-    if imm_str.starts_with("0x") {
-        u8::from_str_radix(imm_str.trim_start_matches("0x"), 16)
+}
+
+/// Check that an instruction got exactly `count` operands.
+fn expect_operands(parts: &[Token], count: usize, usage: &str, line: &SourceLine) -> Result<(), AsmError> {
+    let mnemonic = parts[0].text.to_uppercase();
+    if parts.len() - 1 == count {
+        return Ok(());
+    }
+
+    let message = match count {
+        0 => format!("`{}` takes no operands", mnemonic),
+        1 => format!("`{}` expects 1 operand: {}", mnemonic, usage),
+        _ => format!("`{}` expects {} operands: {}", mnemonic, count, usage),
+    };
+    if parts.len() - 1 > count {
+        // Underline everything past the last expected operand
+        let first = &parts[count + 1];
+        let last = &parts[parts.len() - 1];
+        Err(line.error_at(first.column, last.column + last.text.len() - first.column, message))
     } else {
-        imm_str.parse::<u8>()
-    }.map_err(|_| format!("Invalid immediate value: {}", imm_str).into())
+        Err(line.error(&parts[0], message))
+    }
 }
 
-fn parse_data_address(addr_str: &str) -> Result<usize, Box<dyn Error>> {
-    usize::from_str_radix(addr_str.trim_start_matches("0x"), 16)
-        .map_err(|_| err!("Invalid hex address"))
+/// What an instruction line needs in order to be turned into instructions.
+struct LineContext<'a> {
+    parts: &'a [Token<'a>],
+    line: &'a SourceLine,
+    symbols: &'a SymbolMap,
+    /// Byte address of the first instruction emitted for this line
+    addr: usize,
+    /// Register that pseudo-instructions may clobber, set with `.scratch`
+    scratch: Option<u8>,
+    /// Stack pointer for `CALL`, `RET`, `PUSH` and `POP`, set with `.stack`
+    stack: Option<u8>,
+    /// Bytes the first pass reserved for this line, `None` during the first pass
+    reserved: Option<usize>,
 }
 
-fn parse_data_value(value_str: &str) -> Result<u16, Box<dyn Error>> {
-    u16::from_str_radix(value_str.trim_start_matches("0x"), 16)
-        .map_err(|_| err!("Invalid hex data"))
+impl<'a> LineContext<'a> {
+    /// The first pass only needs sizes, so unresolved symbols are not errors yet.
+    fn sizing(&self) -> bool {
+        self.reserved.is_none()
+    }
+
+    fn mnemonic(&self) -> String {
+        self.parts[0].text.to_uppercase()
+    }
+
+    fn expect_operands(&self, count: usize, usage: &str) -> Result<(), AsmError> {
+        expect_operands(self.parts, count, usage, self.line)
+    }
+
+    /// Check for `count` registers followed by an expression and return the expression.
+    fn expect_registers_and_expression(&self, count: usize, usage: &str) -> Result<Token<'a>, AsmError> {
+        if self.parts.len() < count + 2 {
+            let message = format!("`{}` expects {} operands: {}", self.mnemonic(), count + 1, usage);
+            return Err(self.line.error(&self.parts[0], message));
+        }
+        Ok(operand_span(self.parts, count + 1, self.line))
+    }
+
+    fn register(&self, idx: usize) -> Result<u8, AsmError> {
+        parse_register(&self.parts[idx], self.line)
+    }
+
+    fn immediate(&self, token: &Token) -> Result<u8, AsmError> {
+        if self.sizing() {
+            return Ok(0);
+        }
+        parse_immediate(token, self.symbols, self.line)
+    }
+
+    fn wide_immediate(&self, token: &Token) -> Result<u16, AsmError> {
+        if self.sizing() {
+            return Ok(0);
+        }
+        parse_wide_immediate(token, self.symbols, self.line)
+    }
+
+    fn jump_target(&self, token: &Token) -> Result<u8, AsmError> {
+        if self.sizing() {
+            return Ok(0);
+        }
+        parse_jump_target(token, self.symbols, self.line)
+    }
+
+    /// The value of an expression if every symbol in it is already defined.
+    fn known_value(&self, token: &Token) -> Option<i64> {
+        expr::evaluate(token, self.symbols, self.line).ok()
+    }
 }
 
-fn parse_instruction(parts: &[&str], labels: &LabelMap) -> Result<Instruction, Box<dyn Error>> {
-    match parts[0].to_uppercase().as_str() {
+fn parse_instruction(ctx: &LineContext) -> Result<Vec<Instruction>, AsmError> {
+    let inst = match ctx.mnemonic().as_str() {
         "NOP" => {
-            assert!(parts.len() == 1, "NOP takes no arguments");
-            Ok(Instruction::Nop)
+            ctx.expect_operands(0, "NOP")?;
+            Instruction::Nop
         }
         "ADD" | "LOAD" | "NAND" => {
-            assert!(parts.len() == 3, "{} requires 2 register arguments", parts[0].to_uppercase());
-            let dest = parse_register(parts[1])?;
-            let src = parse_register(parts[2])?;
-            match parts[0].to_uppercase().as_str() {
-                "ADD" => Ok(Instruction::Add { dest, src }),
-                "LOAD" => Ok(Instruction::Load { dest, src }),
-                "NAND" => Ok(Instruction::Nand { dest, src }),
+            ctx.expect_operands(2, "2 registers")?;
+            let dest = ctx.register(1)?;
+            let src = ctx.register(2)?;
+            match ctx.mnemonic().as_str() {
+                "ADD" => Instruction::Add { dest, src },
+                "LOAD" => Instruction::Load { dest, src },
+                "NAND" => Instruction::Nand { dest, src },
                 _ => unreachable!()
             }
         }
         "ADDI" => {
-            assert!(parts.len() == 3, "ADDI requires a register and immediate value");
-            let dest = parse_register(parts[1])?;
-            let imm = parse_immediate(parts[2], labels)?;
-            Ok(Instruction::AddI { dest, imm })
+            let operand = ctx.expect_registers_and_expression(1, "a register and an immediate value")?;
+            let dest = ctx.register(1)?;
+            let imm = ctx.immediate(&operand)?;
+            Instruction::AddI { dest, imm }
         }
         "LOADI" => {
-            assert!(parts.len() == 3, "LOADI requires a register and immediate value");
-            let dest = parse_register(parts[1])?;
-            let imm = parse_immediate(parts[2], labels)?;
-            Ok(Instruction::LoadI { dest, imm })
+            let operand = ctx.expect_registers_and_expression(1, "a register and an immediate value")?;
+            let dest = ctx.register(1)?;
+            let imm = ctx.immediate(&operand)?;
+            Instruction::LoadI { dest, imm }
         }
         "LOADW" => {
-            assert!(parts.len() == 3, "LOADW requires a register and 16-bit immediate value");
-            let dest = parse_register(parts[1])?;
-            // Parse immediate as 16-bit value
-            let imm_str = parts[2];
-            let imm = if imm_str.starts_with("0x") {
-                u16::from_str_radix(imm_str.trim_start_matches("0x"), 16)
-            } else {
-                imm_str.parse::<u16>()
-            }.map_err(|_| format!("Invalid 16-bit immediate value: {}", imm_str))?;
-            Ok(Instruction::LoadW { dest, imm })
+            let operand = ctx.expect_registers_and_expression(1, "a register and a 16-bit immediate value")?;
+            let dest = ctx.register(1)?;
+            let imm = ctx.wide_immediate(&operand)?;
+            Instruction::LoadW { dest, imm }
         }
         "STORE" => {
-            assert!(parts.len() == 3, "STORE requires a register for address and a register for value");
-            let addr = parse_register(parts[1])?;
-            let src = parse_register(parts[2])?;
-            Ok(Instruction::Store { addr, src })
+            ctx.expect_operands(2, "a register for the address and a register for the value")?;
+            let addr = ctx.register(1)?;
+            let src = ctx.register(2)?;
+            Instruction::Store { addr, src }
         }
         "JZ" => {
-            assert!(parts.len() == 3, "JZ requires a register and an address");
-            let reg = parse_register(parts[1])?;
-            let addr = parse_immediate(parts[2], labels)?;
-            Ok(Instruction::Jz { reg, addr })
+            let operand = ctx.expect_registers_and_expression(1, "a register and an address")?;
+            let reg = ctx.register(1)?;
+            let addr = ctx.jump_target(&operand)?;
+            Instruction::Jz { reg, addr }
         }
         "GT" => {
-            assert!(parts.len() == 4, "GT requires 3 register arguments");
-            let dest = parse_register(parts[1])?;
-            let src1 = parse_register(parts[2])?;
-            let src2 = parse_register(parts[3])?;
-            Ok(Instruction::Gt { dest, src1, src2 })
+            ctx.expect_operands(3, "3 registers")?;
+            let dest = ctx.register(1)?;
+            let src1 = ctx.register(2)?;
+            let src2 = ctx.register(3)?;
+            Instruction::Gt { dest, src1, src2 }
         },
         "FLAG" => {
-            assert!(parts.len() == 1, "FLAG takes no arguments");
-            Ok(Instruction::Flag)
+            ctx.expect_operands(0, "FLAG")?;
+            Instruction::Flag
         },
         "HLT" => {
-            assert!(parts.len() == 1, "HLT takes no arguments");
-            Ok(Instruction::Invalid)
+            ctx.expect_operands(0, "HLT")?;
+            Instruction::Invalid
         }
-        _ => Err(err!(format!("Unknown instruction: {}", parts[0])))
-    }
+        _ => return pseudo::expand(ctx),
+    };
+    Ok(vec![inst])
 }
 
-fn encode_instruction(inst: Instruction) -> u16 {
+#[allow(clippy::identity_op, clippy::eq_op)]
+pub fn encode_instruction(inst: Instruction) -> u16 {
     let encoded = match inst {
         Instruction::Nop => {
             0b0000u64                   // NOP opcode (0x0)
@@ -197,156 +552,147 @@ fn encode_instruction(inst: Instruction) -> u16 {
     encoded as u16
 }
 
-fn check_data_overlap(instruction_range: std::ops::Range<usize>, data_sections: &HashMap<usize, u16>) -> Result<(), Box<dyn Error>> {
-    for (addr, _) in data_sections {
-        if instruction_range.contains(addr) {
-            let msg = format!("Data section at address 0x{:x} overlaps with instructions", addr);
-            return Err(err!(msg));
-        }
-    }
-    Ok(())
+/// Settings that apply to a whole assembly run.
+#[derive(Debug, Clone, Default)]
+pub struct AssemblerOptions {
+    /// Directories searched for `.include` files that are not found next to
+    /// the file including them
+    pub include_paths: Vec<PathBuf>,
+    /// Where sections are placed when assembling a single file
+    pub layout: Layout,
+    /// Constants defined before the first line, as with `-D NAME=value`
+    pub defines: Vec<(String, i64)>,
 }
 
-fn merge_instructions_and_data(instructions: Vec<u16>, data_sections: HashMap<usize, u16>) -> Result<Vec<u16>, Box<dyn Error>> {
-    let mut final_memory = vec![0u16; instructions.len()];
-    final_memory.copy_from_slice(&instructions);
-
-    for (addr, value) in data_sections {
-        if addr % 2 != 0 {
-            return Err(err!("Data must be aligned to 2-byte boundaries"));
-        }
-        let idx = addr / 2;
-        if idx >= final_memory.len() {
-            final_memory.resize(idx + 1, 0);
+impl AssemblerOptions {
+    /// Add a define given as `NAME=value`, or `NAME` for the value 1.
+    pub fn define(&mut self, spec: &str) -> Result<(), String> {
+        let (name, value) = spec.split_once('=').unwrap_or((spec, "1"));
+        if !is_symbol_name(name) {
+            return Err(format!("invalid define name `{}`", name));
+        }
+        let parsed = match value.strip_prefix('-') {
+            Some(digits) => parse_number(digits).map(|value| -value),
+            None => parse_number(value),
+        };
+        match parsed {
+            Some(value) => {
+                self.defines.push((name.to_string(), value));
+                Ok(())
+            }
+            None => Err(format!("invalid value `{}` for define `{}`", value, name)),
         }
-        final_memory[idx] = value;
     }
+}
 
-    Ok(final_memory)
+/// Assemble a program, reporting diagnostics against the name `<input>`.
+pub fn assemble(program: &str) -> Result<Vec<u16>, AsmErrors> {
+    assemble_named(program, "<input>")
 }
 
-pub fn assemble(program: &str) -> Result<Vec<u16>, Box<dyn Error>> {
-    let mut instructions = Vec::new();
-    let mut data_sections: HashMap<usize, u16> = HashMap::new();
-    let mut labels: LabelMap = HashMap::new();
-    let mut in_data_section = false;
-    let mut current_data_addr = 0;
-    let mut current_instruction_addr = 0;
+/// Assemble a program, reporting diagnostics against the given file name.
+/// Included files are looked up relative to the directory of `file`.
+pub fn assemble_named(program: &str, file: &str) -> Result<Vec<u16>, AsmErrors> {
+    assemble_source(program, file, &AssemblerOptions::default()).map(|assembly| assembly.words)
+}
 
-    // First pass: collect labels
-    for line in program.lines() {
-        // Remove comments
-        let line = match line.find(';') {
-            Some(idx) => &line[..idx],
-            None => line,
-        };
-        let line = line.trim();
-        if line.is_empty() {
-            continue;
-        }
+/// Assemble the file at `path`, along with any files it includes.
+pub fn assemble_file(path: impl AsRef<Path>) -> Result<Vec<u16>, AsmErrors> {
+    assemble_file_with(path, &AssemblerOptions::default()).map(|assembly| assembly.words)
+}
 
-        // Check for data section directive
-        if line.starts_with(".data") {
-            in_data_section = true;
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() != 2 {
-                return Err(err!(".data directive requires an address"));
-            }
-            current_data_addr = parse_data_address(parts[1])?;
-            continue;
-        }
+/// Assemble the file at `path` with the given options, keeping the listing
+/// and symbol map along with the memory image.
+pub fn assemble_file_with(path: impl AsRef<Path>, options: &AssemblerOptions) -> Result<Assembly, AsmErrors> {
+    let path = path.as_ref();
+    let file = path.display().to_string();
+    match fs::read_to_string(path) {
+        Ok(program) => assemble_source(&program, &file, options),
+        Err(e) => Err(AsmErrors(vec![AsmError::new(&file, 0, 0, 0, "", format!("cannot read `{}`: {}", file, e))])),
+    }
+}
 
-        if line == ".text" {
-            in_data_section = false;
-            continue;
-        }
+/// Assemble a program named `file` with the given options, keeping the
+/// listing and symbol map along with the memory image.
+pub fn assemble_source(program: &str, file: &str, options: &AssemblerOptions) -> Result<Assembly, AsmErrors> {
+    encode(&parse_named(program, file), options)
+}
 
-        if in_data_section {
-            current_data_addr += 2; // Each data value is 2 bytes
-            continue;
-        }
+/// Lay out and encode a parsed program with the sections placed by the
+/// options' layout. Statements are assembled from their parts, so changes
+/// made to the tree after parsing are encoded.
+pub fn encode(program: &Program, options: &AssemblerOptions) -> Result<Assembly, AsmErrors> {
+    let (object, mut errors) = build_object(program, options, &options.layout.fixed_addresses());
+    let assembly = link::link_objects(std::slice::from_ref(&object), &options.layout, &mut errors);
+    finish(assembly, errors)
+}
 
-        // Check for label (ends with :)
-        if line.ends_with(':') {
-            let label = line[..line.len()-1].trim().to_string();
-            labels.insert(label, current_instruction_addr);
-            continue;
-        }
+/// Lay out a parsed program into an object for the linker, which encodes it.
+pub fn encode_object(program: &Program, options: &AssemblerOptions) -> Result<Object, AsmErrors> {
+    let (object, errors) = build_object(program, options, &HashMap::new());
+    finish(Some(object), errors)
+}
 
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if !parts.is_empty() {
-            // Account for LOADW taking 2 words
-            if parts[0].to_uppercase() == "LOADW" {
-                current_instruction_addr += 4;
-            } else {
-                current_instruction_addr += 2;
-            }
-        }
+/// Assemble a program to run from byte address `base`, so that labels and
+/// jump targets refer to where it is loaded rather than to address 0.
+pub fn assemble_at(program: &str, base: usize) -> Result<Assembly, AsmErrors> {
+    if !base.is_multiple_of(2) || base > 0xFFFF {
+        let message = format!("load address 0x{:x} must be even and at most 0xffff", base);
+        return Err(AsmErrors(vec![AsmError::new("<input>", 0, 0, 0, "", message)]));
     }
+    let options = AssemblerOptions { layout: Layout::at(base), ..Default::default() };
+    assemble_source(program, "<input>", &options)
+}
 
-    // Reset for second pass
-    in_data_section = false;
-    current_data_addr = 0;
-
-    // Second pass: assemble instructions with label resolution
-    for line in program.lines() {
-        // Remove comments
-        let line = match line.find(';') {
-            Some(idx) => &line[..idx],
-            None => line,
-        };
-        let line = line.trim();
-        if line.is_empty() {
-            continue;
-        }
-
-        // Check for data section directive
-        if line.starts_with(".data") {
-            in_data_section = true;
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() != 2 {
-                return Err(err!(".data directive requires an address"));
-            }
-            current_data_addr = parse_data_address(parts[1])?;
-            continue;
-        }
+/// Assemble a program into an object for the linker. No section is assumed
+/// to be at a particular address, so the object can be placed anywhere.
+pub fn assemble_object(program: &str, file: &str, options: &AssemblerOptions) -> Result<Object, AsmErrors> {
+    encode_object(&parse_named(program, file), options)
+}
 
-        if line == ".text" {
-            in_data_section = false;
-            continue;
-        }
+/// Assemble the file at `path` into an object for the linker.
+pub fn assemble_object_file(path: impl AsRef<Path>, options: &AssemblerOptions) -> Result<Object, AsmErrors> {
+    let path = path.as_ref();
+    let file = path.display().to_string();
+    match fs::read_to_string(path) {
+        Ok(program) => assemble_object(&program, &file, options),
+        Err(e) => Err(AsmErrors(vec![AsmError::new(&file, 0, 0, 0, "", format!("cannot read `{}`: {}", file, e))])),
+    }
+}
 
-        if in_data_section {
-            let value = parse_data_value(line)?;
-            data_sections.insert(current_data_addr, value);
-            current_data_addr += 2; // Each data value is 2 bytes
-            continue;
-        }
+/// Read includes, expand macros, lower structured control flow and run
+/// the first pass.
+fn build_object(program: &Program, options: &AssemblerOptions, fixed: &HashMap<String, usize>) -> (Object, KeyedErrors) {
+    let file = program.file.as_str();
+    // Errors from reading includes and expanding macros come first, as the
+    // sort on line index that puts the rest in source order is stable
+    let mut macro_errors = Vec::new();
 
-        // Skip label definitions in second pass
-        if line.ends_with(':') {
-            continue;
-        }
+    // Defines become constants ahead of the source, so objects carry them
+    let command_line: Rc<str> = "<command line>".into();
+    let mut lines: Vec<SourceLine> = options.defines.iter()
+        .enumerate()
+        .map(|(idx, (name, value))| SourceLine::new(&command_line, idx + 1, &format!(".equ {} {}", name, value)))
+        .collect();
+    lines.extend(program.source_lines());
+    let lines = include::load_source(lines, file, &options.include_paths, &mut macro_errors);
+    let lines = macros::expand_macros(lines, &mut macro_errors);
+    let lines = structured::lower(lines, &mut macro_errors);
 
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.is_empty() {
-            continue;
-        }
+    let mut line_errors = Vec::new();
+    let object = object::build(file, lines, fixed, &mut line_errors);
+    let errors = macro_errors.into_iter().map(|e| ((0, 0), e))
+        .chain(line_errors.into_iter().map(|(idx, e)| ((0, idx), e)))
+        .collect();
+    (object, errors)
+}
 
-        let inst = parse_instruction(&parts, &labels)?;
-        let encoded = encode_instruction(inst.clone());
-        instructions.push(encoded);
-        
-        // For LOADW, add the immediate value as a second word
-        if let Instruction::LoadW { imm, .. } = inst {
-            instructions.push(imm);
+fn finish<T>(result: Option<T>, mut errors: KeyedErrors) -> Result<T, AsmErrors> {
+    match result {
+        Some(result) if errors.is_empty() => Ok(result),
+        _ => {
+            errors.sort_by_key(|(key, _)| *key);
+            Err(AsmErrors(errors.into_iter().map(|(_, e)| e).collect()))
         }
     }
-
-    // Check for overlaps between instructions and data sections
-    let instruction_range = 0..(instructions.len() * 2); // Each instruction is 2 bytes
-    check_data_overlap(instruction_range, &data_sections)?;
-
-    // Merge instructions and data
-    merge_instructions_and_data(instructions, data_sections)
-} 
\ No newline at end of file
+}
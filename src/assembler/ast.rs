@@ -0,0 +1,240 @@
+use super::data::split_operands;
+use super::names::numeric_reference;
+use super::{format, is_symbol_name, label_definition, operand_span, SourceLine, Token};
+use std::fmt;
+use std::rc::Rc;
+
+/// Where a piece of a statement is in its file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    /// 1-based column of the first character
+    pub column: usize,
+    pub len: usize,
+}
+
+/// A word of a statement, such as a mnemonic or label name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Word {
+    pub text: String,
+    pub span: Span,
+}
+
+/// What an operand is, as far as can be told without knowing the symbols.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OperandKind {
+    /// `r0` to `r7`
+    Register(u8),
+    /// A bare name: a label, a local or numeric label reference, a constant
+    /// or a register alias
+    Symbol(String),
+    /// Anything else as written, such as a number, `x + 1` or a string
+    /// literal, which may have spaces in it
+    Expression(String),
+}
+
+impl OperandKind {
+    fn classify(text: &str) -> Self {
+        let register = text.strip_prefix(['r', 'R'])
+            .filter(|digit| digit.len() == 1)
+            .and_then(|digit| digit.parse::<u8>().ok())
+            .filter(|&reg| reg < 8);
+        match register {
+            Some(reg) => OperandKind::Register(reg),
+            None if is_symbol_name(text) || numeric_reference(text).is_some() => OperandKind::Symbol(text.to_string()),
+            None => OperandKind::Expression(text.to_string()),
+        }
+    }
+}
+
+/// An operand of a directive or instruction, or a bare data value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Operand {
+    pub kind: OperandKind,
+    pub span: Span,
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            OperandKind::Register(reg) => write!(f, "r{}", reg),
+            OperandKind::Symbol(text) | OperandKind::Expression(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatementKind {
+    /// A blank or comment-only line
+    Empty,
+    /// `name:`, without the colon
+    Label(Word),
+    /// A line starting with `.`, such as `.data 0x1000` or `.equ SIZE 4`
+    Directive { name: Word, operands: Vec<Operand> },
+    /// An instruction, pseudo-instruction or macro invocation
+    Instruction { mnemonic: Word, operands: Vec<Operand> },
+    /// A bare value inside a `.data` block
+    Data(Operand),
+}
+
+/// One line of a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    pub kind: StatementKind,
+    /// The comment, including its `;` or `#`
+    pub comment: Option<Word>,
+    /// Whether the operands were separated by commas rather than spaces
+    pub commas: bool,
+    /// The whole line
+    pub span: Span,
+    /// The text of the line as it was parsed
+    pub source: String,
+}
+
+impl Statement {
+    /// The statement as a line of source, laid out from its parts.
+    pub fn to_source(&self) -> String {
+        let code = format::render(self);
+        match &self.comment {
+            Some(comment) if code.is_empty() => comment.text.clone(),
+            Some(comment) => format!("{} {}", code, comment.text),
+            None => code,
+        }
+    }
+
+    /// Whether the parts of `self` and `other` are the same, apart from spans.
+    fn same_parts(&self, other: &Statement) -> bool {
+        format::render(self) == format::render(other)
+            && self.comment.as_ref().map(|comment| &comment.text) == other.comment.as_ref().map(|comment| &comment.text)
+    }
+}
+
+/// A parsed source file. Parsing only splits lines into their parts: it
+/// never fails, and the meaning of each statement is checked when the
+/// program is encoded. Statements may be changed, added or removed before
+/// encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub file: String,
+    pub statements: Vec<Statement>,
+}
+
+impl Program {
+    /// The statements as source lines, for the assembler's passes, which work
+    /// on lines. Each line is laid out from the statement's parts, operands
+    /// from their kinds, so changes to them are what gets assembled. A
+    /// statement whose parts still match its `source` keeps that text, so
+    /// diagnostics point at the columns in the file.
+    pub(super) fn source_lines(&self) -> Vec<SourceLine> {
+        let file: Rc<str> = self.file.as_str().into();
+        let sources: Vec<&str> = self.statements.iter().map(|statement| statement.source.as_str()).collect();
+        // Parsed together, as bare values are only data after `.data`
+        let parsed = parse_named(&sources.join("\n"), &self.file);
+        self.statements.iter().enumerate().map(|(idx, statement)| {
+            let text = match parsed.statements.get(idx) {
+                Some(original) if statement.same_parts(original) => statement.source.clone(),
+                _ => statement.to_source(),
+            };
+            SourceLine::new(&file, statement.span.line, &text)
+        }).collect()
+    }
+}
+
+fn word(line: &SourceLine, token: &Token) -> Word {
+    Word { text: token.text.to_string(), span: Span { line: line.number, column: token.column + 1, len: token.text.len() } }
+}
+
+fn operand(line: &SourceLine, token: &Token) -> Operand {
+    let Word { text, span } = word(line, token);
+    Operand { kind: OperandKind::classify(&text), span }
+}
+
+/// Whether two whitespace-separated tokens belong to the same expression,
+/// as in `x + 1` or `(a << 2)`. A leading `-` or `+` starts a new operand,
+/// so `LOADW r0 -1` has two.
+fn continues_expression(prev: &str, next: &str) -> bool {
+    prev.ends_with(['+', '-', '*', '/', '&', '|', '<', '>', '=', '!', '~', '('])
+        || next.starts_with(['*', '/', '&', '|', '<', '>', '=', ')'])
+        || next.starts_with("!=")
+        || next == "+"
+        || next == "-"
+}
+
+/// The operands after the first token: split at commas if the line has any,
+/// otherwise at whitespace, keeping expressions together.
+fn operands(line: &SourceLine, parts: &[Token]) -> (Vec<Operand>, bool) {
+    if parts.len() < 2 {
+        return (Vec::new(), false);
+    }
+    let by_comma = split_operands(&operand_span(parts, 1, line));
+    if by_comma.len() > 1 {
+        return (by_comma.iter().map(|token| operand(line, token)).collect(), true);
+    }
+
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    for (idx, token) in parts.iter().enumerate().skip(1) {
+        match groups.last_mut() {
+            Some((_, last)) if continues_expression(parts[*last].text, token.text) => *last = idx,
+            _ => groups.push((idx, idx)),
+        }
+    }
+    let operands = groups.into_iter()
+        .map(|(first, last)| operand(line, &operand_span(&parts[first..=last], 0, line)))
+        .collect();
+    (operands, false)
+}
+
+/// Parse a program, naming it `<input>` in spans and diagnostics.
+pub fn parse(program: &str) -> Program {
+    parse_named(program, "<input>")
+}
+
+/// Parse a program read from `file`.
+pub fn parse_named(program: &str, file: &str) -> Program {
+    let name: Rc<str> = file.into();
+    let mut statements = Vec::new();
+    // Bare values are data between `.data` and the next section change
+    let mut data = false;
+
+    for (idx, text) in program.lines().enumerate() {
+        let line = SourceLine::new(&name, idx + 1, text);
+        let parts = line.tokens();
+        let code_len = line.code().len();
+        let comment = text[code_len..].trim_end();
+        let comment = (!comment.is_empty()).then(|| Word {
+            text: comment.to_string(),
+            span: Span { line: idx + 1, column: code_len + 1, len: comment.len() },
+        });
+
+        let (operands, commas) = operands(&line, &parts);
+        let kind = match parts.first() {
+            None => StatementKind::Empty,
+            Some(_) if label_definition(&parts).is_some() => {
+                let mut label = word(&line, &parts[0]);
+                label.text.pop();
+                label.span.len -= 1;
+                StatementKind::Label(label)
+            }
+            Some(first) if first.text.starts_with('.') => {
+                match first.text {
+                    ".data" => data = true,
+                    ".text" | ".section" => data = false,
+                    _ => {}
+                }
+                StatementKind::Directive { name: word(&line, first), operands }
+            }
+            Some(_) if data => StatementKind::Data(operand(&line, &operand_span(&parts, 0, &line))),
+            Some(first) => StatementKind::Instruction { mnemonic: word(&line, first), operands },
+        };
+
+        statements.push(Statement {
+            kind,
+            comment,
+            commas,
+            span: Span { line: idx + 1, column: 1, len: text.len() },
+            source: text.to_string(),
+        });
+    }
+
+    Program { file: file.to_string(), statements }
+}
@@ -0,0 +1,354 @@
+use super::ast::{Operand, OperandKind, Program, Span, Statement, StatementKind, Word};
+use super::data;
+use super::format::{format_program, INDENT};
+use super::{encode, is_symbol_name, AssemblerOptions, Instruction};
+use crate::error::{AsmError, AsmErrors};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Generated label names start with this, and named labels may not.
+const GENERATED_PREFIX: &str = "__label_";
+
+/// The file name of programs built by a [`ProgramBuilder`].
+const FILE: &str = "<builder>";
+
+/// Tells builders apart, so a label cannot be used with another builder.
+static NEXT_BUILDER: AtomicUsize = AtomicUsize::new(0);
+
+/// A label created by a [`ProgramBuilder`], which can be bound to one place
+/// in the program and used any number of times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label {
+    builder: usize,
+    index: usize,
+}
+
+/// A 16-bit operand: a number or the address of a label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    Number(i64),
+    Label(Label),
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Self {
+        Value::Number(value.into())
+    }
+}
+
+impl From<u16> for Value {
+    fn from(value: u16) -> Self {
+        Value::Number(value.into())
+    }
+}
+
+impl From<u8> for Value {
+    fn from(value: u8) -> Self {
+        Value::Number(value.into())
+    }
+}
+
+impl From<Label> for Value {
+    fn from(label: Label) -> Self {
+        Value::Label(label)
+    }
+}
+
+/// An operand for [`ProgramBuilder::push`], with the mistake to report at
+/// it, if any.
+type Part = (OperandKind, Option<String>);
+
+/// Builds a program from Rust instead of source text, one statement at a
+/// time. Registers are numbered as in [`Instruction`]. Mistakes such as a
+/// register above r7, a label that is never bound, an invalid label name
+/// or a value that does not fit are reported by [`ProgramBuilder::finish`],
+/// at their line in [`ProgramBuilder::source`].
+#[derive(Debug, Clone)]
+pub struct ProgramBuilder {
+    id: usize,
+    statements: Vec<Statement>,
+    labels: Vec<String>,
+    /// Names passed to `named_label` that could not be used, by label,
+    /// until the label first appears
+    invalid: HashMap<usize, String>,
+    /// Mistakes found while building, reported ahead of the assembler's
+    errors: Vec<AsmError>,
+}
+
+impl Default for ProgramBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgramBuilder {
+    pub fn new() -> Self {
+        ProgramBuilder {
+            id: NEXT_BUILDER.fetch_add(1, Ordering::Relaxed),
+            statements: Vec::new(),
+            labels: Vec::new(),
+            invalid: HashMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Add `kind` as the next line, laid out as `format` would lay it out.
+    fn statement(&mut self, kind: StatementKind) -> &Statement {
+        let line = self.statements.len() + 1;
+        let mut statement = Statement {
+            kind,
+            comment: None,
+            commas: false,
+            span: Span { line, column: 1, len: 0 },
+            source: String::new(),
+        };
+        if let StatementKind::Directive { operands, .. } = &statement.kind {
+            statement.commas = operands.len() > 1;
+        }
+        statement.source = statement.to_source();
+        statement.span.len = statement.source.len();
+        self.statements.push(statement);
+        &self.statements[line - 1]
+    }
+
+    /// Add an instruction or, if `name` starts with `.`, a directive, and
+    /// report the mistakes found in its operands where they are.
+    fn push(&mut self, name: &str, parts: Vec<Part>) -> &mut Self {
+        let line = self.statements.len() + 1;
+        let directive = name.starts_with('.');
+        let mut column = match directive && !data::is_directive(name) {
+            true => 1,
+            false => INDENT.len() + 1,
+        };
+        let word = Word { text: name.to_string(), span: Span { line, column, len: name.len() } };
+        column += name.len() + 1;
+
+        let separator = if directive && parts.len() > 1 { 2 } else { 1 };
+        let mut operands = Vec::new();
+        let mut mistakes = Vec::new();
+        for (kind, mistake) in parts {
+            let mut operand = Operand { kind, span: Span { line, column, len: 0 } };
+            operand.span.len = operand.to_string().len();
+            column += operand.span.len + separator;
+            mistakes.extend(mistake.map(|message| (operand.span, message)));
+            operands.push(operand);
+        }
+
+        let kind = match directive {
+            true => StatementKind::Directive { name: word, operands },
+            false => StatementKind::Instruction { mnemonic: word, operands },
+        };
+        let source = self.statement(kind).source.clone();
+        for (span, message) in mistakes {
+            self.errors.push(AsmError::new(FILE, line, span.column, span.len, &source, message));
+        }
+        self
+    }
+
+    /// Create a label named `name`, which appears in the symbol map. Names
+    /// starting with `__label_` are kept for generated labels, and local or
+    /// numeric label names cannot be used.
+    pub fn named_label(&mut self, name: &str) -> Label {
+        if !is_symbol_name(name) || name.starts_with('.') || name.starts_with(GENERATED_PREFIX) {
+            // The label still works, under a generated name
+            let label = self.label();
+            self.invalid.insert(label.index, name.to_string());
+            return label;
+        }
+        self.labels.push(name.to_string());
+        Label { builder: self.id, index: self.labels.len() - 1 }
+    }
+
+    /// Create a label with a generated name.
+    pub fn label(&mut self) -> Label {
+        self.labels.push(format!("{}{}", GENERATED_PREFIX, self.labels.len()));
+        Label { builder: self.id, index: self.labels.len() - 1 }
+    }
+
+    /// The name of `label` and the mistake to report where it first
+    /// appears. A label from another builder has no name here.
+    fn label_name(&mut self, label: Label) -> (Option<String>, Option<String>) {
+        match self.labels.get(label.index) {
+            Some(name) if label.builder == self.id => {
+                let mistake = self.invalid.remove(&label.index).map(|name| format!("invalid label name `{}`", name));
+                (Some(name.clone()), mistake)
+            }
+            _ => (None, Some("label was created by a different `ProgramBuilder`".to_string())),
+        }
+    }
+
+    /// Place `label` at the current address.
+    pub fn bind(&mut self, label: Label) -> &mut Self {
+        let line = self.statements.len() + 1;
+        let (name, mistake) = self.label_name(label);
+        let source = match name {
+            Some(name) => {
+                let span = Span { line, column: 1, len: name.len() };
+                self.statement(StatementKind::Label(Word { text: name, span })).source.clone()
+            }
+            // Reported at the line that follows, where the label would be
+            None => String::new(),
+        };
+        if let Some(message) = mistake {
+            self.errors.push(AsmError::new(FILE, line, 1, source.len().saturating_sub(1), &source, message));
+        }
+        self
+    }
+
+    /// `value` as a 16-bit operand or, if `jump` is set, as an address JZ
+    /// can reach.
+    fn value(&mut self, value: Value, jump: bool) -> Part {
+        let number = match value {
+            Value::Number(number) => number,
+            Value::Label(label) => {
+                let (name, mistake) = self.label_name(label);
+                // Stands in for the label, whose mistake is reported
+                let kind = name.map_or_else(|| OperandKind::Expression("0".to_string()), OperandKind::Symbol);
+                return (kind, mistake);
+            }
+        };
+        let fits = match jump {
+            true => (0..=0xff).contains(&number),
+            false => (-0x8000..=0xffff).contains(&number),
+        };
+        match (fits, jump) {
+            (true, _) => (OperandKind::Expression(number.to_string()), None),
+            (false, true) => {
+                let message = format!("jump target {} is outside 0x00-0xff, the addresses JZ can reach", number);
+                (OperandKind::Expression("0".to_string()), Some(message))
+            }
+            (false, false) => {
+                let message = format!("{} does not fit in 16 bits", number);
+                (OperandKind::Expression("0".to_string()), Some(message))
+            }
+        }
+    }
+
+    /// Emit any instruction.
+    pub fn instruction(&mut self, inst: Instruction) -> &mut Self {
+        let reg = |reg: u8| (OperandKind::Register(reg), None);
+        let number = |number: String| (OperandKind::Expression(number), None);
+        let (mnemonic, parts) = match inst {
+            Instruction::Nop => ("NOP", vec![]),
+            Instruction::Add { dest, src } => ("ADD", vec![reg(dest), reg(src)]),
+            Instruction::AddI { dest, imm } => ("ADDI", vec![reg(dest), number(imm.to_string())]),
+            Instruction::Nand { dest, src } => ("NAND", vec![reg(dest), reg(src)]),
+            Instruction::LoadI { dest, imm } => ("LOADI", vec![reg(dest), number(imm.to_string())]),
+            Instruction::Store { addr, src } => ("STORE", vec![reg(addr), reg(src)]),
+            Instruction::Load { dest, src } => ("LOAD", vec![reg(dest), reg(src)]),
+            Instruction::Jz { reg: test, addr } => ("JZ", vec![reg(test), number(addr.to_string())]),
+            Instruction::LoadW { dest, imm } => ("LOADW", vec![reg(dest), number(format!("0x{:04X}", imm))]),
+            Instruction::Gt { dest, src1, src2 } => ("GT", vec![reg(dest), reg(src1), reg(src2)]),
+            Instruction::Flag => ("FLAG", vec![]),
+            Instruction::Invalid => ("HLT", vec![]),
+        };
+        self.push(mnemonic, parts)
+    }
+
+    pub fn nop(&mut self) -> &mut Self {
+        self.instruction(Instruction::Nop)
+    }
+
+    pub fn add(&mut self, dest: u8, src: u8) -> &mut Self {
+        self.instruction(Instruction::Add { dest, src })
+    }
+
+    pub fn addi(&mut self, dest: u8, imm: u8) -> &mut Self {
+        self.instruction(Instruction::AddI { dest, imm })
+    }
+
+    pub fn nand(&mut self, dest: u8, src: u8) -> &mut Self {
+        self.instruction(Instruction::Nand { dest, src })
+    }
+
+    pub fn loadi(&mut self, dest: u8, imm: u8) -> &mut Self {
+        self.instruction(Instruction::LoadI { dest, imm })
+    }
+
+    pub fn store(&mut self, addr: u8, src: u8) -> &mut Self {
+        self.instruction(Instruction::Store { addr, src })
+    }
+
+    pub fn load(&mut self, dest: u8, src: u8) -> &mut Self {
+        self.instruction(Instruction::Load { dest, src })
+    }
+
+    /// Jump to `target` if `reg` is zero. The target must be in the first
+    /// 256 bytes of memory.
+    pub fn jz(&mut self, reg: u8, target: impl Into<Value>) -> &mut Self {
+        let target = self.value(target.into(), true);
+        self.push("JZ", vec![(OperandKind::Register(reg), None), target])
+    }
+
+    pub fn loadw(&mut self, dest: u8, imm: impl Into<Value>) -> &mut Self {
+        let imm = self.value(imm.into(), false);
+        self.push("LOADW", vec![(OperandKind::Register(dest), None), imm])
+    }
+
+    pub fn gt(&mut self, dest: u8, src1: u8, src2: u8) -> &mut Self {
+        self.instruction(Instruction::Gt { dest, src1, src2 })
+    }
+
+    pub fn flag(&mut self) -> &mut Self {
+        self.instruction(Instruction::Flag)
+    }
+
+    pub fn hlt(&mut self) -> &mut Self {
+        self.instruction(Instruction::Invalid)
+    }
+
+    /// Emit 16-bit words, like `.word`.
+    pub fn words<V: Into<Value>>(&mut self, values: impl IntoIterator<Item = V>) -> &mut Self {
+        let values: Vec<Value> = values.into_iter().map(Into::into).collect();
+        let parts = values.into_iter().map(|value| self.value(value, false)).collect();
+        self.push(".word", parts)
+    }
+
+    /// Emit bytes, like `.byte`.
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        let parts = bytes.iter().map(|byte| (OperandKind::Expression(byte.to_string()), None)).collect();
+        self.push(".byte", parts)
+    }
+
+    /// Emit `count` zero bytes, like `.space`.
+    pub fn space(&mut self, count: usize) -> &mut Self {
+        self.push(".space", vec![(OperandKind::Expression(count.to_string()), None)])
+    }
+
+    /// Pad with zero bytes up to a multiple of `align`, like `.align`.
+    pub fn align(&mut self, align: usize) -> &mut Self {
+        self.push(".align", vec![(OperandKind::Expression(align.to_string()), None)])
+    }
+
+    /// Continue at `addr`, like `.org`.
+    pub fn org(&mut self, addr: u16) -> &mut Self {
+        self.push(".org", vec![(OperandKind::Expression(format!("{:#x}", addr)), None)])
+    }
+
+    /// The program built so far as assembly source.
+    pub fn source(&self) -> String {
+        format_program(&self.program())
+    }
+
+    /// The program built so far.
+    pub fn program(&self) -> Program {
+        Program { file: FILE.to_string(), statements: self.statements.clone() }
+    }
+
+    /// Assemble the program, giving the same words as `assemble(&self.source())`
+    /// unless building it went wrong.
+    pub fn finish(&self) -> Result<Vec<u16>, AsmErrors> {
+        let result = encode(&self.program(), &AssemblerOptions::default()).map(|assembly| assembly.words);
+        match result {
+            Ok(words) if self.errors.is_empty() => Ok(words),
+            Ok(_) => Err(AsmErrors(self.errors.clone())),
+            Err(AsmErrors(errors)) => Err(AsmErrors(self.errors.iter().cloned().chain(errors).collect())),
+        }
+    }
+}
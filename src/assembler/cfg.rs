@@ -0,0 +1,452 @@
+use super::{Assembly, Instruction, SourceLine, SymbolKind};
+use crate::disassembler::decode_instruction;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use std::rc::Rc;
+
+/// Clock cycles each instruction takes: one in the fetch state and one in
+/// the execute state. The value of `LOAD` and `LOADW` arrives during the
+/// next fetch, so they take no longer.
+pub const CYCLES_PER_INSTRUCTION: usize = 2;
+
+/// Register values, where they are known. Every register is 0 after reset.
+pub(super) type Values = [Option<u16>; 8];
+
+/// How the jump that ends a `CALL` or `RET` moves between routines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    /// Into a routine, which comes back to the next instruction
+    Call,
+    /// Back to whichever instruction follows the `CALL`
+    Return,
+}
+
+/// An instruction of an assembled program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeInstruction {
+    pub address: usize,
+    pub instruction: Instruction,
+    /// Index of the listing line it was assembled from
+    pub line: usize,
+    /// Set on the last instruction of a `CALL` or `RET`
+    pub transfer: Option<Transfer>,
+}
+
+impl CodeInstruction {
+    fn next(&self) -> usize {
+        self.address + self.instruction.size()
+    }
+}
+
+/// The instructions of an assembled program, by address. Data is left out,
+/// since the listing knows which lines are code.
+pub(super) fn decode(assembly: &Assembly) -> Vec<CodeInstruction> {
+    let mut code = Vec::new();
+    for (line, listed) in assembly.listing.iter().enumerate() {
+        let mut address = match listed.address {
+            Some(address) if listed.code => address,
+            _ => continue,
+        };
+        let source = SourceLine::new(&Rc::from(listed.file.as_str()), listed.line, &listed.source);
+        let transfer = match source.tokens().first().map(|token| token.text.to_uppercase()).as_deref() {
+            Some("CALL") => Some(Transfer::Call),
+            Some("RET") => Some(Transfer::Return),
+            _ => None,
+        };
+        let words = listed.words();
+        let mut word = 0;
+        while word < words.len() {
+            let (instruction, len) = decode_instruction(words[word], words.get(word + 1).copied());
+            word += len;
+            let transfer = transfer.filter(|_| word == words.len());
+            code.push(CodeInstruction { address, instruction, line, transfer });
+            address += len * 2;
+        }
+    }
+    code.sort_by_key(|inst| inst.address);
+    code
+}
+
+/// The register `inst` writes, with the value it writes if `values` decide it.
+pub(super) fn write(inst: &Instruction, values: &Values) -> Option<(u8, Option<u16>)> {
+    let value = |reg: u8| values[reg as usize];
+    match *inst {
+        Instruction::Add { dest, src } => Some((dest, value(dest).zip(value(src)).map(|(a, b)| a.wrapping_add(b)))),
+        Instruction::AddI { dest, imm } => Some((dest, value(dest).map(|a| a.wrapping_add(imm.into())))),
+        Instruction::Nand { dest, src } => Some((dest, value(dest).zip(value(src)).map(|(a, b)| !(a & b)))),
+        Instruction::Gt { dest, src1, src2 } => Some((dest, value(src1).zip(value(src2)).map(|(a, b)| (a > b).into()))),
+        Instruction::LoadI { dest, imm } => Some((dest, Some(imm.into()))),
+        Instruction::LoadW { dest, imm } => Some((dest, Some(imm))),
+        Instruction::Load { dest, .. } => Some((dest, None)),
+        _ => None,
+    }
+}
+
+/// The addresses execution can continue at after `inst`. A `JZ` on a
+/// register that `values` decide only goes one way, which is how `JMP`
+/// (`LOADI` of 0, then `JZ`) is told apart from a branch. A `RET` leaves
+/// for the instruction after its `CALL`, see [`return_site`].
+pub(super) fn successors(inst: &CodeInstruction, values: &Values) -> Vec<usize> {
+    if inst.transfer == Some(Transfer::Return) {
+        return Vec::new();
+    }
+    match inst.instruction {
+        Instruction::Invalid => Vec::new(),
+        Instruction::Jz { reg, addr } => match values[reg as usize] {
+            Some(0) => vec![addr.into()],
+            Some(_) => vec![inst.next()],
+            None => vec![addr.into(), inst.next()],
+        },
+        _ => vec![inst.next()],
+    }
+}
+
+/// Where execution continues once the routine `inst` calls returns. The
+/// routine may change any register by then.
+pub(super) fn return_site(inst: &CodeInstruction) -> Option<usize> {
+    (inst.transfer == Some(Transfer::Call)).then(|| inst.next())
+}
+
+/// Register values known on entry to each instruction, or `None` where no
+/// path from `entry` reaches it.
+fn propagate(code: &[CodeInstruction], entry: usize) -> Vec<Option<Values>> {
+    let at: HashMap<usize, usize> = code.iter().enumerate().map(|(idx, inst)| (inst.address, idx)).collect();
+    let mut states: Vec<Option<Values>> = vec![None; code.len()];
+    states[entry] = Some([Some(0); 8]);
+    let mut work = vec![entry];
+    while let Some(idx) = work.pop() {
+        let values = states[idx].unwrap();
+        let mut after = values;
+        if let Some((dest, value)) = write(&code[idx].instruction, &values) {
+            after[dest as usize] = value;
+        }
+        let returned = return_site(&code[idx]).map(|address| (address, [None; 8]));
+        for (address, after) in successors(&code[idx], &values).into_iter().map(|address| (address, after)).chain(returned) {
+            let Some(&next) = at.get(&address) else { continue };
+            let joined = match states[next] {
+                Some(old) => {
+                    let mut joined = old;
+                    for (value, other) in joined.iter_mut().zip(after) {
+                        *value = value.filter(|value| other == Some(*value));
+                    }
+                    joined
+                }
+                None => after,
+            };
+            if states[next] != Some(joined) {
+                states[next] = Some(joined);
+                work.push(next);
+            }
+        }
+    }
+    states
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// The next block, when a `JZ` is not taken or the block has no jump
+    FallThrough,
+    /// A `JZ` that may or may not be taken
+    Branch,
+    /// A `JZ` on a register that is always zero there, such as `JMP`
+    Jump,
+    /// The instruction after a `CALL`, once the routine returns
+    Return,
+}
+
+impl EdgeKind {
+    fn name(self) -> &'static str {
+        match self {
+            EdgeKind::FallThrough => "fallthrough",
+            EdgeKind::Branch => "branch",
+            EdgeKind::Jump => "jump",
+            EdgeKind::Return => "return",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    /// Index of the block in `Cfg::blocks`
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+/// A run of instructions that is only entered at its first instruction and
+/// only left after its last.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    /// The first label at its start, if any
+    pub label: Option<String>,
+    pub start: usize,
+    /// The address after its last instruction
+    pub end: usize,
+    pub instructions: Vec<CodeInstruction>,
+    pub successors: Vec<Edge>,
+    /// Addresses outside the program that execution continues at, such as
+    /// the end of the code when the block does not halt or jump
+    pub escapes: Vec<usize>,
+    /// Whether the block ends in `HLT` or an invalid opcode
+    pub halts: bool,
+    /// Whether any path from the entry reaches it
+    pub reachable: bool,
+    /// Clock cycles to run the whole block
+    pub cycles: usize,
+}
+
+impl Block {
+    /// A name for the block in graphs: its label or its address.
+    pub fn name(&self) -> String {
+        match &self.label {
+            Some(label) => label.clone(),
+            None => format!("0x{:04x}", self.start),
+        }
+    }
+}
+
+/// A natural loop: the blocks that can reach a back edge to `header`
+/// without passing through `header` itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    pub header: usize,
+    /// Every block of the loop, the header included, in address order
+    pub blocks: Vec<usize>,
+    /// The blocks that jump back to the header
+    pub latches: Vec<usize>,
+}
+
+/// The control-flow graph of an assembled program, starting at its lowest
+/// code address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    /// Blocks in address order; the first is the entry
+    pub blocks: Vec<Block>,
+    /// The immediate dominator of each block, which is `None` for the entry
+    /// and for blocks no path reaches
+    pub dominators: Vec<Option<usize>>,
+    pub loops: Vec<Loop>,
+}
+
+impl Cfg {
+    pub fn new(assembly: &Assembly) -> Cfg {
+        let code = decode(assembly);
+        if code.is_empty() {
+            return Cfg { blocks: Vec::new(), dominators: Vec::new(), loops: Vec::new() };
+        }
+        let states = propagate(&code, 0);
+        let at: HashMap<usize, usize> = code.iter().enumerate().map(|(idx, inst)| (inst.address, idx)).collect();
+
+        // Blocks start at the entry, at jump targets, after jumps and halts,
+        // and after gaps in the code
+        let mut leaders = BTreeSet::from([0]);
+        for (idx, inst) in code.iter().enumerate() {
+            if let Instruction::Jz { addr, .. } = inst.instruction {
+                let targets = successors(inst, &states[idx].unwrap_or([None; 8]));
+                if targets.contains(&addr.into()) {
+                    leaders.extend(at.get(&addr.into()));
+                }
+            }
+            let ends_block = matches!(inst.instruction, Instruction::Jz { .. } | Instruction::Invalid);
+            if idx + 1 < code.len() && (ends_block || code[idx + 1].address != inst.next()) {
+                leaders.insert(idx + 1);
+            }
+        }
+
+        let starts: Vec<usize> = leaders.into_iter().collect();
+        let block_at: HashMap<usize, usize> = starts.iter().enumerate().map(|(block, &idx)| (code[idx].address, block)).collect();
+        let mut blocks = Vec::new();
+        for (block, &first) in starts.iter().enumerate() {
+            let last = starts.get(block + 1).map_or(code.len(), |&next| next) - 1;
+            let terminator = &code[last];
+            let label = assembly.symbols.iter()
+                .find(|symbol| symbol.kind == SymbolKind::Label && symbol.value == code[first].address as i64)
+                .map(|symbol| symbol.name.clone());
+
+            let targets = successors(terminator, &states[last].unwrap_or([None; 8]));
+            let mut successors = Vec::new();
+            let mut escapes = Vec::new();
+            let mut edges: Vec<(usize, EdgeKind)> = targets.iter()
+                .map(|&target| match terminator.instruction {
+                    Instruction::Jz { addr, .. } if target == usize::from(addr) && targets.len() == 2 => (target, EdgeKind::Branch),
+                    Instruction::Jz { addr, .. } if target == usize::from(addr) => (target, EdgeKind::Jump),
+                    _ => (target, EdgeKind::FallThrough),
+                })
+                .collect();
+            edges.extend(return_site(terminator).map(|address| (address, EdgeKind::Return)));
+            for (target, kind) in edges {
+                match block_at.get(&target) {
+                    Some(&to) => successors.push(Edge { to, kind }),
+                    None => escapes.push(target),
+                }
+            }
+
+            let instructions = code[first..=last].to_vec();
+            blocks.push(Block {
+                label,
+                start: code[first].address,
+                end: terminator.next(),
+                cycles: instructions.len() * CYCLES_PER_INSTRUCTION,
+                instructions,
+                successors,
+                escapes,
+                halts: targets.is_empty() && terminator.transfer.is_none(),
+                reachable: states[first].is_some(),
+            });
+        }
+
+        let dominates = dominator_sets(&blocks);
+        let dominators = (0..blocks.len())
+            .map(|block| {
+                let strict: Vec<usize> = (0..blocks.len()).filter(|&d| d != block && dominates[block][d]).collect();
+                // The closest dominator is the one the others dominate
+                strict.iter().copied().find(|&d| strict.iter().all(|&other| dominates[d][other]))
+            })
+            .collect();
+        let loops = natural_loops(&blocks, &dominates);
+        Cfg { blocks, dominators, loops }
+    }
+
+    /// Whether every path from the entry to `block` passes through `dominator`.
+    pub fn dominates(&self, dominator: usize, block: usize) -> bool {
+        let mut current = Some(block);
+        while let Some(idx) = current {
+            if idx == dominator {
+                return self.blocks[block].reachable;
+            }
+            current = self.dominators[idx];
+        }
+        false
+    }
+
+    /// The graph in Graphviz DOT format, with each block's instructions and
+    /// cycle count.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+        for (idx, block) in self.blocks.iter().enumerate() {
+            let mut label = format!("{} ({} cycles)\\l", block.name(), block.cycles);
+            for inst in &block.instructions {
+                label.push_str(&format!("{:04x}  {}\\l", inst.address, inst.instruction));
+            }
+            let style = match (block.reachable, self.loops.iter().any(|l| l.header == idx)) {
+                (false, _) => ", style=dashed",
+                (true, true) => ", style=bold",
+                (true, false) => "",
+            };
+            dot.push_str(&format!("    b{} [label=\"{}\"{}];\n", idx, label, style));
+        }
+        for (idx, block) in self.blocks.iter().enumerate() {
+            for edge in &block.successors {
+                dot.push_str(&format!("    b{} -> b{} [label=\"{}\"];\n", idx, edge.to, edge.kind.name()));
+            }
+            for escape in &block.escapes {
+                dot.push_str(&format!("    b{} -> \"0x{:04x}\" [style=dashed];\n", idx, escape));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// The graph as JSON, with blocks referring to each other by index.
+    pub fn to_json(&self) -> String {
+        let blocks: Vec<_> = self.blocks.iter().zip(&self.dominators)
+            .map(|(block, dominator)| json!({
+                "name": block.name(),
+                "label": block.label,
+                "start": block.start,
+                "end": block.end,
+                "cycles": block.cycles,
+                "reachable": block.reachable,
+                "halts": block.halts,
+                "dominator": dominator,
+                "instructions": block.instructions.iter()
+                    .map(|inst| json!({ "address": inst.address, "text": inst.instruction.to_string() }))
+                    .collect::<Vec<_>>(),
+                "successors": block.successors.iter()
+                    .map(|edge| json!({ "to": edge.to, "kind": edge.kind.name() }))
+                    .collect::<Vec<_>>(),
+                "escapes": block.escapes,
+            }))
+            .collect();
+        let loops: Vec<_> = self.loops.iter()
+            .map(|l| json!({ "header": l.header, "blocks": l.blocks, "latches": l.latches }))
+            .collect();
+        json!({ "blocks": blocks, "loops": loops }).to_string()
+    }
+}
+
+fn predecessors(blocks: &[Block]) -> Vec<Vec<usize>> {
+    let mut predecessors = vec![Vec::new(); blocks.len()];
+    for (idx, block) in blocks.iter().enumerate() {
+        for edge in &block.successors {
+            predecessors[edge.to].push(idx);
+        }
+    }
+    predecessors
+}
+
+/// For each block, which blocks dominate it. Unreachable blocks have none.
+fn dominator_sets(blocks: &[Block]) -> Vec<Vec<bool>> {
+    let predecessors = predecessors(blocks);
+    let count = blocks.len();
+    let mut dominates: Vec<Vec<bool>> = blocks.iter()
+        .enumerate()
+        .map(|(idx, block)| match idx {
+            0 => (0..count).map(|d| d == 0).collect(),
+            _ => vec![block.reachable; count],
+        })
+        .collect();
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for idx in 1..count {
+            if !blocks[idx].reachable {
+                continue;
+            }
+            let mut set = vec![true; count];
+            for &pred in predecessors[idx].iter().filter(|&&pred| blocks[pred].reachable) {
+                for (d, dominated) in set.iter_mut().enumerate() {
+                    *dominated &= dominates[pred][d];
+                }
+            }
+            set[idx] = true;
+            if set != dominates[idx] {
+                dominates[idx] = set;
+                changed = true;
+            }
+        }
+    }
+    dominates
+}
+
+fn natural_loops(blocks: &[Block], dominates: &[Vec<bool>]) -> Vec<Loop> {
+    let predecessors = predecessors(blocks);
+    let mut loops: Vec<Loop> = Vec::new();
+    for (latch, block) in blocks.iter().enumerate() {
+        for edge in &block.successors {
+            let header = edge.to;
+            if !block.reachable || !dominates[latch][header] {
+                continue;
+            }
+            // Walk back from the latch until the header
+            let mut body = BTreeSet::from([header]);
+            let mut work = vec![latch];
+            while let Some(idx) = work.pop() {
+                if body.insert(idx) {
+                    work.extend(predecessors[idx].iter().filter(|&&pred| blocks[pred].reachable));
+                }
+            }
+
+            match loops.iter_mut().find(|l| l.header == header) {
+                Some(existing) => {
+                    body.extend(existing.blocks.iter().copied());
+                    existing.blocks = body.into_iter().collect();
+                    existing.latches.push(latch);
+                }
+                None => loops.push(Loop { header, blocks: body.into_iter().collect(), latches: vec![latch] }),
+            }
+        }
+    }
+    loops.sort_by_key(|l| l.header);
+    loops
+}
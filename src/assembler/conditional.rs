@@ -0,0 +1,147 @@
+use super::{expr, is_symbol_name, operand_span, SourceLine, SymbolMap, Token};
+use crate::error::AsmError;
+use std::collections::HashSet;
+
+/// An open `.if` block.
+struct Block {
+    /// The line that opened the block, for reporting a missing `.endif`
+    opener: SourceLine,
+    /// Whether the lines of the current branch are assembled
+    active: bool,
+    /// Whether an earlier branch was taken, so the later ones are skipped
+    taken: bool,
+    /// Whether the block is past its `.else`
+    in_else: bool,
+    /// Whether this is an `.ifz`, which is decided at run time
+    runtime: bool,
+}
+
+/// Tracks `.if`/`.elif`/`.else`/`.endif` and `.ifdef`/`.ifndef` blocks as
+/// lines are read. Conditions are decided before any label is placed, so
+/// they can only use defines and `.equ` constants defined before them.
+///
+/// `.ifz` blocks share `.else` and `.endif`, but test a register at run
+/// time, so their directives are kept for the structured lowering.
+pub(super) struct Conditionals {
+    blocks: Vec<Block>,
+    /// Constants whose value is known so far
+    constants: SymbolMap,
+    /// Every constant name so far, known or not, for `.ifdef`
+    defined: HashSet<String>,
+}
+
+impl Conditionals {
+    pub(super) fn new() -> Self {
+        Conditionals { blocks: Vec::new(), constants: SymbolMap::new(), defined: HashSet::new() }
+    }
+
+    /// Whether lines at this point are assembled.
+    pub(super) fn active(&self) -> bool {
+        self.blocks.last().is_none_or(|block| block.active)
+    }
+
+    /// Whether the block enclosing the innermost one is active.
+    fn parent_active(&self) -> bool {
+        self.blocks.iter().rev().nth(1).is_none_or(|block| block.active)
+    }
+
+    fn condition(&self, parts: &[Token], line: &SourceLine, errors: &mut Vec<AsmError>) -> bool {
+        let directive = parts[0].text;
+        if parts.len() < 2 {
+            errors.push(line.error(&parts[0], format!("`{}` requires a condition", directive)));
+            return false;
+        }
+        if directive == ".ifdef" || directive == ".ifndef" {
+            if parts.len() > 2 || !is_symbol_name(parts[1].text) {
+                errors.push(line.error(&parts[1], format!("`{}` expects a single symbol name", directive)));
+                return false;
+            }
+            return self.defined.contains(parts[1].text) == (directive == ".ifdef");
+        }
+
+        match expr::evaluate(&operand_span(parts, 1, line), &self.constants, line) {
+            Ok(value) => value != 0,
+            Err(mut e) => {
+                if e.message.starts_with("undefined symbol") {
+                    e.message.push_str("; conditions can only use defines and `.equ` constants defined before them");
+                }
+                errors.push(e);
+                false
+            }
+        }
+    }
+
+    /// Handle the next line, returning whether it should be assembled.
+    /// Conditional directives themselves never are.
+    pub(super) fn keep(&mut self, line: &SourceLine, errors: &mut Vec<AsmError>) -> bool {
+        let parts = line.tokens();
+        let directive = match parts.first() {
+            Some(token) => token.text,
+            None => return self.active(),
+        };
+
+        match directive {
+            ".if" | ".ifdef" | ".ifndef" => {
+                // Conditions in skipped lines are not evaluated, so they cannot fail
+                let active = self.active() && self.condition(&parts, line, errors);
+                self.blocks.push(Block { opener: line.clone(), active, taken: active, in_else: false, runtime: false });
+            }
+            ".ifz" => {
+                let active = self.active();
+                self.blocks.push(Block { opener: line.clone(), active, taken: true, in_else: false, runtime: true });
+                return active;
+            }
+            ".elif" | ".else" => {
+                let (taken, in_else, runtime) = match self.blocks.last() {
+                    Some(block) => (block.taken, block.in_else, block.runtime),
+                    None => {
+                        errors.push(line.error(&parts[0], format!("`{}` without a matching `.if`", directive)));
+                        return false;
+                    }
+                };
+                if in_else {
+                    errors.push(line.error(&parts[0], format!("`{}` after `.else`", directive)));
+                }
+                if runtime {
+                    if directive == ".elif" {
+                        errors.push(line.error(&parts[0], "`.elif` cannot follow `.ifz`; nest another `.ifz` instead"));
+                        return false;
+                    }
+                    let block = self.blocks.last_mut().unwrap();
+                    block.in_else = true;
+                    return block.active;
+                }
+                let active = !in_else && !taken && self.parent_active()
+                    && (directive == ".else" || self.condition(&parts, line, errors));
+                let block = self.blocks.last_mut().unwrap();
+                block.active = active;
+                block.taken |= active;
+                block.in_else |= directive == ".else";
+            }
+            ".endif" => match self.blocks.pop() {
+                Some(block) if block.runtime => return block.active,
+                Some(_) => {}
+                None => errors.push(line.error(&parts[0], "`.endif` without a matching `.if`")),
+            },
+            _ => {
+                let active = self.active();
+                if active && directive == ".equ" && parts.len() >= 3 {
+                    self.defined.insert(parts[1].text.to_string());
+                    if let Ok(value) = expr::evaluate(&operand_span(&parts, 2, line), &self.constants, line) {
+                        self.constants.insert(parts[1].text.to_string(), value);
+                    }
+                }
+                return active;
+            }
+        }
+        false
+    }
+
+    /// Report the blocks that were never closed.
+    pub(super) fn finish(self, errors: &mut Vec<AsmError>) {
+        for block in self.blocks {
+            let token = block.opener.tokens()[0];
+            errors.push(block.opener.error(&token, format!("unterminated `{}`, expected `.endif`", token.text)));
+        }
+    }
+}
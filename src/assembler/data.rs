@@ -0,0 +1,200 @@
+use super::{operand_span, LineContext, SourceLine, Token};
+use crate::error::AsmError;
+
+/// Whether `name` is a directive that emits data.
+pub(super) fn is_directive(name: &str) -> bool {
+    matches!(name, ".word" | ".byte" | ".ascii" | ".asciz" | ".fill" | ".space" | ".align")
+}
+
+/// Whether a directive emits whole words, and so must start at an even address.
+pub(super) fn is_word_directive(name: &str) -> bool {
+    matches!(name, ".word" | ".fill")
+}
+
+/// The length of the quoted literal at the start of `text`, quotes included.
+pub(super) fn quoted_len(text: &str) -> Option<usize> {
+    let quote = text.chars().next()?;
+    let mut escaped = false;
+    for (idx, c) in text.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            _ if c == quote => return Some(idx + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Decode the escapes in the body of a quoted literal. `column` is where the
+/// body starts on the line, for errors.
+pub(super) fn unescape(body: &str, column: usize, line: &SourceLine) -> Result<Vec<u8>, AsmError> {
+    let mut bytes = Vec::new();
+    let mut chars = body.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        let byte = match chars.next() {
+            Some((_, 'n')) => b'\n',
+            Some((_, 't')) => b'\t',
+            Some((_, 'r')) => b'\r',
+            Some((_, '0')) => 0,
+            Some((_, '\\')) => b'\\',
+            Some((_, '\'')) => b'\'',
+            Some((_, '"')) => b'"',
+            Some((_, 'x')) => {
+                let digits = body.get(idx + 2..idx + 4).and_then(|digits| u8::from_str_radix(digits, 16).ok());
+                match digits {
+                    Some(byte) => {
+                        chars.next();
+                        chars.next();
+                        byte
+                    }
+                    None => return Err(line.error_at(column + idx, 2, "`\\x` must be followed by 2 hex digits")),
+                }
+            }
+            Some((_, other)) => {
+                return Err(line.error_at(column + idx, 1 + other.len_utf8(), format!("unknown escape `\\{}`", other)));
+            }
+            None => return Err(line.error_at(column + idx, 1, "unfinished escape at the end of the literal")),
+        };
+        bytes.push(byte);
+    }
+    Ok(bytes)
+}
+
+/// Split a comma-separated operand list, leaving commas inside quotes alone.
+pub(super) fn split_operands<'a>(token: &Token<'a>) -> Vec<Token<'a>> {
+    let text = token.text;
+    let mut operands = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    while pos < text.len() {
+        let rest = &text[pos..];
+        if rest.starts_with('"') || rest.starts_with('\'') {
+            pos += quoted_len(rest).unwrap_or(rest.len());
+            continue;
+        }
+        if rest.starts_with(',') {
+            operands.push((start, pos));
+            start = pos + 1;
+        }
+        pos += rest.chars().next().unwrap().len_utf8();
+    }
+    operands.push((start, text.len()));
+
+    operands.into_iter()
+        .map(|(start, end)| {
+            let item = &text[start..end];
+            let trimmed = item.trim_start();
+            Token { text: trimmed.trim_end(), column: token.column + start + item.len() - trimmed.len() }
+        })
+        .collect()
+}
+
+pub(super) fn string(token: &Token, line: &SourceLine) -> Result<Vec<u8>, AsmError> {
+    match quoted_len(token.text) {
+        Some(len) if token.text.starts_with('"') && len == token.text.len() => {
+            unescape(&token.text[1..len - 1], token.column + 1, line)
+        }
+        _ => Err(line.error(token, format!("expected a string literal, found `{}`", token.text))),
+    }
+}
+
+impl LineContext<'_> {
+    /// A value that must fit in `bits`, signed or unsigned. Values are only
+    /// needed in the second pass.
+    fn data_value(&self, token: &Token, bits: u32) -> Result<u16, AsmError> {
+        if self.sizing() {
+            return Ok(0);
+        }
+        let value = super::expr::evaluate(token, self.symbols, self.line)?;
+        let min = -(1i64 << (bits - 1));
+        let max = (1i64 << bits) - 1;
+        if value < min || value > max {
+            let what = if bits == 8 { "a byte" } else { "a word" };
+            return Err(self.line.error(token, format!("`{}` evaluates to {}, which does not fit in {}", token.text, value, what)));
+        }
+        Ok((value & max) as u16)
+    }
+
+    /// A count that decides the size of the line, so it must be known in the first pass.
+    fn count(&self, token: &Token) -> Result<usize, AsmError> {
+        match self.known_value(token) {
+            Some(count) if (0..=0x10000).contains(&count) => Ok(count as usize),
+            Some(count) => Err(self.line.error(token, format!("count {} is out of range", count))),
+            None => Err(self.line.error(token, format!(
+                "`{}` count must be a constant defined before this line", self.parts[0].text
+            ))),
+        }
+    }
+}
+
+/// The bytes a data directive emits. `ctx.addr` is the address to align
+/// from; while sizing, values that are not known yet are emitted as zeros.
+pub(super) fn emit(ctx: &LineContext) -> Result<Vec<u8>, AsmError> {
+    let name = ctx.parts[0].text;
+    if ctx.parts.len() < 2 {
+        return Err(ctx.line.error(&ctx.parts[0], format!("`{}` requires at least one operand", name)));
+    }
+    let operands = split_operands(&operand_span(ctx.parts, 1, ctx.line));
+    let optional = |idx: usize, bits: u32| match operands.get(idx) {
+        Some(token) => ctx.data_value(token, bits),
+        None => Ok(0),
+    };
+    let max_operands = match name {
+        ".fill" | ".space" | ".align" => 2,
+        _ => usize::MAX,
+    };
+    if operands.len() > max_operands {
+        return Err(ctx.line.error(&operands[max_operands], format!("`{}` takes at most {} operands", name, max_operands)));
+    }
+
+    let mut bytes = Vec::new();
+    match name {
+        ".word" => {
+            for token in &operands {
+                bytes.extend_from_slice(&ctx.data_value(token, 16)?.to_le_bytes());
+            }
+        }
+        ".byte" => {
+            for token in &operands {
+                bytes.push(ctx.data_value(token, 8)? as u8);
+            }
+        }
+        ".ascii" | ".asciz" => {
+            for token in &operands {
+                bytes.extend(string(token, ctx.line)?);
+                if name == ".asciz" {
+                    bytes.push(0);
+                }
+            }
+        }
+        ".fill" => {
+            let count = ctx.count(&operands[0])?;
+            let value = optional(1, 16)?;
+            bytes = value.to_le_bytes().repeat(count);
+        }
+        ".space" => {
+            let count = ctx.count(&operands[0])?;
+            bytes = vec![optional(1, 8)? as u8; count];
+        }
+        ".align" => {
+            let align = ctx.count(&operands[0])?;
+            if !align.is_power_of_two() {
+                return Err(ctx.line.error(&operands[0], format!("alignment {} is not a power of 2", align)));
+            }
+            // The second pass keeps the padding the first pass worked out
+            let padding = match ctx.reserved {
+                Some(reserved) => reserved,
+                None => (align - ctx.addr % align) % align,
+            };
+            bytes = vec![optional(1, 8)? as u8; padding];
+        }
+        _ => unreachable!(),
+    }
+    Ok(bytes)
+}
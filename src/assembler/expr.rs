@@ -0,0 +1,249 @@
+use super::data::{quoted_len, unescape};
+use super::names::numeric_reference;
+use super::{parse_number, SourceLine, SymbolMap, Token};
+use crate::error::AsmError;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    Number(i64),
+    Symbol(String),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+/// A piece of an expression with its byte range inside the operand text.
+struct Lexeme {
+    kind: Kind,
+    start: usize,
+    end: usize,
+}
+
+// Longer operators come first so `<<` is not read as `<`
+const OPERATORS: [&str; 20] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "<", ">", "+", "-", "*", "/", "&", "|", "~", "!", "(", ")",
+];
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+fn lex(token: &Token, line: &SourceLine) -> Result<Vec<Lexeme>, AsmError> {
+    let text = token.text;
+    let mut lexemes = Vec::new();
+    let mut pos = 0;
+
+    while pos < text.len() {
+        let rest = &text[pos..];
+        let c = rest.chars().next().unwrap();
+
+        if c.is_whitespace() {
+            pos += c.len_utf8();
+            continue;
+        }
+
+        if is_symbol_char(c) {
+            let len = rest.find(|c: char| !is_symbol_char(c)).unwrap_or(rest.len());
+            let word = &rest[..len];
+            let kind = if c.is_ascii_digit() {
+                match parse_number(word) {
+                    Some(value) => Kind::Number(value),
+                    None if numeric_reference(word).is_some() => Kind::Symbol(word.to_string()),
+                    None => return Err(line.error_at(token.column + pos, len, format!("invalid number `{}`", word))),
+                }
+            } else {
+                Kind::Symbol(word.to_string())
+            };
+            lexemes.push(Lexeme { kind, start: pos, end: pos + len });
+            pos += len;
+            continue;
+        }
+
+        if c == '\'' {
+            let len = match quoted_len(rest) {
+                Some(len) => len,
+                None => return Err(line.error_at(token.column + pos, rest.len(), "unterminated character literal")),
+            };
+            let bytes = unescape(&rest[1..len - 1], token.column + pos + 1, line)?;
+            // A pair packs the first character into the high byte, as the
+            // CPU's `FLAG` comparison with "os" does
+            let value = match bytes[..] {
+                [byte] => byte as i64,
+                [high, low] => u16::from_be_bytes([high, low]) as i64,
+                _ => return Err(line.error_at(token.column + pos, len, "character literal must hold 1 or 2 bytes")),
+            };
+            lexemes.push(Lexeme { kind: Kind::Number(value), start: pos, end: pos + len });
+            pos += len;
+            continue;
+        }
+
+        match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            Some(&op) => {
+                let kind = match op {
+                    "(" => Kind::Open,
+                    ")" => Kind::Close,
+                    _ => Kind::Op(op),
+                };
+                lexemes.push(Lexeme { kind, start: pos, end: pos + op.len() });
+                pos += op.len();
+            }
+            None => {
+                return Err(line.error_at(token.column + pos, c.len_utf8(), format!("unexpected character `{}` in expression", c)));
+            }
+        }
+    }
+
+    Ok(lexemes)
+}
+
+/// Recursive descent over the lexemes, using C operator precedence:
+/// `||` < `&&` < `|` < `&` < `== !=` < `< <= > >=` < `<< >>` < `+ -` < `* /` < unary `- ~ !`.
+/// Comparisons and logical operators give 1 for true and 0 for false.
+struct Parser<'a> {
+    lexemes: Vec<Lexeme>,
+    pos: usize,
+    token: &'a Token<'a>,
+    symbols: &'a SymbolMap,
+    line: &'a SourceLine,
+}
+
+impl Parser<'_> {
+    fn error(&self, start: usize, end: usize, message: impl Into<String>) -> AsmError {
+        self.line.error_at(self.token.column + start, end - start, message)
+    }
+
+    fn error_here(&self, message: impl Into<String>) -> AsmError {
+        match self.lexemes.get(self.pos) {
+            Some(lexeme) => self.error(lexeme.start, lexeme.end, message),
+            None => self.error(self.token.text.len(), self.token.text.len() + 1, message),
+        }
+    }
+
+    fn peek_op(&self, ops: &[&str]) -> Option<&'static str> {
+        match self.lexemes.get(self.pos) {
+            Some(Lexeme { kind: Kind::Op(op), .. }) if ops.contains(op) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, AsmError> {
+        const LEVELS: [&[&str]; 9] = [
+            &["||"], &["&&"], &["|"], &["&"], &["==", "!="], &["<", "<=", ">", ">="], &["<<", ">>"], &["+", "-"], &["*", "/"],
+        ];
+
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = self.peek_op(LEVELS[level]) {
+            let op_lexeme = self.pos;
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = match op {
+                "|" => lhs | rhs,
+                "&" => lhs & rhs,
+                "+" => lhs.wrapping_add(rhs),
+                "-" => lhs.wrapping_sub(rhs),
+                "*" => lhs.wrapping_mul(rhs),
+                "||" => (lhs != 0 || rhs != 0) as i64,
+                "&&" => (lhs != 0 && rhs != 0) as i64,
+                "==" => (lhs == rhs) as i64,
+                "!=" => (lhs != rhs) as i64,
+                "<" => (lhs < rhs) as i64,
+                "<=" => (lhs <= rhs) as i64,
+                ">" => (lhs > rhs) as i64,
+                ">=" => (lhs >= rhs) as i64,
+                "/" | "<<" | ">>" => {
+                    let Lexeme { start, end, .. } = self.lexemes[op_lexeme];
+                    match op {
+                        "/" if rhs == 0 => return Err(self.error(start, end, "division by zero")),
+                        "/" => lhs.wrapping_div(rhs),
+                        _ if !(0..64).contains(&rhs) => {
+                            return Err(self.error(start, end, format!("shift amount {} is out of range", rhs)));
+                        }
+                        "<<" => lhs << rhs,
+                        _ => lhs >> rhs,
+                    }
+                }
+                _ => unreachable!(),
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<i64, AsmError> {
+        match self.peek_op(&["-", "~", "!", "+"]) {
+            Some(op) => {
+                self.pos += 1;
+                let value = self.unary()?;
+                Ok(match op {
+                    "-" => value.wrapping_neg(),
+                    "~" => !value,
+                    "!" => (value == 0) as i64,
+                    _ => value,
+                })
+            }
+            None => self.primary(),
+        }
+    }
+
+    fn expect_close(&mut self) -> Result<(), AsmError> {
+        match self.lexemes.get(self.pos) {
+            Some(Lexeme { kind: Kind::Close, .. }) => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(self.error_here("expected `)`")),
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, AsmError> {
+        let (kind, start, end) = match self.lexemes.get(self.pos) {
+            Some(lexeme) => (lexeme.kind.clone(), lexeme.start, lexeme.end),
+            None => return Err(self.error_here("expected a value")),
+        };
+        self.pos += 1;
+
+        match kind {
+            Kind::Number(value) => Ok(value),
+            Kind::Open => {
+                let value = self.binary(0)?;
+                self.expect_close()?;
+                Ok(value)
+            }
+            Kind::Symbol(name) => {
+                let is_call = matches!(self.lexemes.get(self.pos), Some(Lexeme { kind: Kind::Open, .. }));
+                match name.to_lowercase().as_str() {
+                    "lo" | "hi" if is_call => {
+                        self.pos += 1;
+                        let value = self.binary(0)?;
+                        self.expect_close()?;
+                        if name.eq_ignore_ascii_case("lo") {
+                            Ok(value & 0xFF)
+                        } else {
+                            Ok((value >> 8) & 0xFF)
+                        }
+                    }
+                    _ => self.symbols.get(self.line.resolve(&name)).copied()
+                        .ok_or_else(|| self.error(start, end, format!("undefined symbol `{}`", name))),
+                }
+            }
+            Kind::Op(op) => Err(self.error(start, end, format!("expected a value, found `{}`", op))),
+            Kind::Close => Err(self.error(start, end, "expected a value, found `)`")),
+        }
+    }
+}
+
+/// Evaluate a constant expression. `token` spans the whole expression so
+/// errors can point at the offending part of it.
+pub(super) fn evaluate(token: &Token, symbols: &SymbolMap, line: &SourceLine) -> Result<i64, AsmError> {
+    let lexemes = lex(token, line)?;
+    let mut parser = Parser { lexemes, pos: 0, token, symbols, line };
+
+    let value = parser.binary(0)?;
+    if parser.pos < parser.lexemes.len() {
+        return Err(parser.error_here("unexpected token in expression"));
+    }
+    Ok(value)
+}
@@ -0,0 +1,95 @@
+use super::ast::{parse_named, Operand, Program, Statement, StatementKind};
+use super::data::{self, quoted_len};
+
+/// Indentation of instructions, data and data directives.
+pub(super) const INDENT: &str = "        ";
+
+/// Column that trailing comments start at, unless the code is longer.
+const COMMENT_COLUMN: usize = 32;
+
+/// `text` with each run of whitespace outside of literals replaced by one space.
+fn collapse_whitespace(text: &str) -> String {
+    let mut result = String::new();
+    let mut pos = 0;
+    while pos < text.len() {
+        let rest = &text[pos..];
+        let c = rest.chars().next().unwrap();
+        if c.is_whitespace() {
+            let len = rest.find(|c: char| !c.is_whitespace()).unwrap_or(rest.len());
+            result.push(' ');
+            pos += len;
+        } else if c == '"' || c == '\'' {
+            let len = quoted_len(rest).unwrap_or(rest.len());
+            result.push_str(&rest[..len]);
+            pos += len;
+        } else {
+            result.push(c);
+            pos += c.len_utf8();
+        }
+    }
+    result
+}
+
+fn join(operands: &[Operand], commas: bool) -> String {
+    let operands: Vec<String> = operands.iter().map(|operand| collapse_whitespace(&operand.to_string())).collect();
+    operands.join(if commas { ", " } else { " " })
+}
+
+/// The code of a statement, laid out the standard way, without its comment.
+pub(super) fn render(statement: &Statement) -> String {
+    let line = match &statement.kind {
+        StatementKind::Empty => String::new(),
+        StatementKind::Label(name) => format!("{}:", name.text),
+        StatementKind::Directive { name, operands } => {
+            let indent = if data::is_directive(&name.text) { INDENT } else { "" };
+            format!("{}{} {}", indent, name.text, join(operands, statement.commas))
+        }
+        StatementKind::Instruction { mnemonic, operands } => {
+            // Mnemonics and macro names are case-insensitive, but words such as
+            // `\op` in a macro body are substituted before they are looked up
+            let mnemonic = match mnemonic.text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                true => mnemonic.text.to_uppercase(),
+                false => mnemonic.text.clone(),
+            };
+            format!("{}{} {}", INDENT, mnemonic, join(operands, statement.commas))
+        }
+        StatementKind::Data(value) => format!("{}{}", INDENT, collapse_whitespace(&value.to_string())),
+    };
+    line.trim_end().to_string()
+}
+
+/// Lay out a parsed program the standard way: labels and section directives
+/// at the start of the line, everything else indented, mnemonics in upper
+/// case, registers in lower case, operands separated by single spaces (or
+/// commas and a space, if the line used commas) and trailing comments
+/// aligned.
+pub fn format_program(program: &Program) -> String {
+    let mut text = String::new();
+    for statement in &program.statements {
+        let code = render(statement);
+        match &statement.comment {
+            None => text.push_str(&code),
+            // Comments on their own keep whether they were indented
+            Some(comment) if code.is_empty() => {
+                if comment.span.column > 1 {
+                    text.push_str(INDENT);
+                }
+                text.push_str(&comment.text);
+            }
+            Some(comment) => {
+                let width = code.chars().count();
+                text.push_str(&code);
+                text.push_str(&" ".repeat(COMMENT_COLUMN.saturating_sub(width).max(1)));
+                text.push_str(&comment.text);
+            }
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Format the source of a program. Only whitespace changes, along with the
+/// case of mnemonics and registers, so the program assembles to the same words.
+pub fn format(program: &str) -> String {
+    format_program(&parse_named(program, "<input>"))
+}
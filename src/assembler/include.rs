@@ -0,0 +1,111 @@
+use super::conditional::Conditionals;
+use super::{operand_span, SourceLine};
+use crate::error::AsmError;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+struct Loader<'a> {
+    /// Directories searched after the including file's own directory
+    include_paths: &'a [PathBuf],
+    /// Canonical paths of the files currently being read, outermost first
+    stack: Vec<PathBuf>,
+    /// Conditional blocks read so far, so includes in skipped lines are not
+    /// read. Their errors are reported when macros are expanded.
+    conditionals: Conditionals,
+    /// Whether the lines are in a `.macro` definition, whose conditions
+    /// depend on its arguments and are left to its expansions
+    in_macro: bool,
+    output: Vec<SourceLine>,
+    errors: Vec<AsmError>,
+}
+
+/// The file named by `.include "name"`, without its quotes.
+fn included_name(line: &SourceLine) -> Result<&str, AsmError> {
+    let parts = line.tokens();
+    if parts.len() < 2 {
+        return Err(line.error(&parts[0], ".include directive requires a quoted file name"));
+    }
+    let operand = operand_span(&parts, 1, line);
+    match operand.text.strip_prefix('"').and_then(|name| name.strip_suffix('"')) {
+        Some(name) if !name.is_empty() => Ok(name),
+        _ => Err(line.error(&operand, format!("expected a quoted file name, found `{}`", operand.text))),
+    }
+}
+
+impl Loader<'_> {
+    /// Find `name` next to the including file, then in each include path.
+    fn resolve(&self, name: &str, dir: &Path) -> Option<PathBuf> {
+        std::iter::once(dir)
+            .chain(self.include_paths.iter().map(PathBuf::as_path))
+            .map(|base| base.join(name))
+            .find(|path| path.is_file())
+    }
+
+    fn include(&mut self, line: &SourceLine, dir: &Path) -> Result<(), AsmError> {
+        let name = included_name(line)?;
+        let parts = line.tokens();
+        let operand = operand_span(&parts, 1, line);
+
+        let path = self.resolve(name, dir)
+            .ok_or_else(|| line.error(&operand, format!("cannot find included file `{}`", name)))?;
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if self.stack.contains(&canonical) {
+            let chain: Vec<String> = self.stack.iter()
+                .skip_while(|entry| **entry != canonical)
+                .chain(std::iter::once(&canonical))
+                .map(|entry| entry.display().to_string())
+                .collect();
+            return Err(line.error(&operand, format!("include cycle: {}", chain.join(" -> "))));
+        }
+        let text = fs::read_to_string(&path)
+            .map_err(|e| line.error(&operand, format!("cannot read `{}`: {}", path.display(), e)))?;
+
+        let file: Rc<str> = path.display().to_string().into();
+        let lines = text.lines().enumerate().map(|(idx, text)| SourceLine::new(&file, idx + 1, text));
+        self.stack.push(canonical);
+        self.load(lines, path.parent().unwrap_or(Path::new("")));
+        self.stack.pop();
+        Ok(())
+    }
+
+    fn load(&mut self, lines: impl IntoIterator<Item = SourceLine>, dir: &Path) {
+        for line in lines {
+            let first = line.tokens().first().map(|token| token.text.to_string());
+            let active = self.in_macro || self.conditionals.keep(&line, &mut Vec::new());
+            match first.as_deref() {
+                Some(".macro") if active => self.in_macro = true,
+                Some(".endm") if self.in_macro => self.in_macro = false,
+                // A skipped `.include` is kept for the macro expansion to drop
+                Some(".include") if active => {
+                    if let Err(e) = self.include(&line, dir) {
+                        self.errors.push(e);
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            self.output.push(line);
+        }
+    }
+}
+
+/// Replace each `.include` in the lines of `file` with the lines of the file
+/// it names. Included files are looked up relative to the file that
+/// includes them first, then in `include_paths`. Includes in blocks that
+/// conditional assembly skips are not read.
+pub(super) fn load_source(lines: Vec<SourceLine>, file: &str, include_paths: &[PathBuf], errors: &mut Vec<AsmError>) -> Vec<SourceLine> {
+    let path = Path::new(file);
+    let mut loader = Loader {
+        include_paths,
+        stack: fs::canonicalize(path).into_iter().collect(),
+        conditionals: Conditionals::new(),
+        in_macro: false,
+        output: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    loader.load(lines, path.parent().unwrap_or(Path::new("")));
+    errors.extend(loader.errors);
+    loader.output
+}
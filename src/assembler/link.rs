@@ -0,0 +1,494 @@
+use super::object::{Object, Place};
+use super::{
+    data, encode_instruction, expr, is_symbol_name, label_definition, operand_span, parse_data_value, parse_instruction, pseudo,
+    parse_number, Assembly, Instruction, LineContext, ListingLine, Segment, SourceLine, Symbol, SymbolKind,
+    SymbolMap,
+};
+use crate::error::{AsmError, AsmErrors};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+/// Errors keyed by object and line index, so they can be sorted into source order.
+pub(super) type KeyedErrors = Vec<((usize, usize), AsmError)>;
+
+/// A line of a layout file: a section and where it starts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutEntry {
+    pub section: String,
+    /// Start address, or `None` to follow the previous section
+    pub address: Option<usize>,
+}
+
+/// Where the linker places each section. Sections are placed in the order
+/// they are listed, and sections the layout does not name follow the last
+/// one in the order objects first use them.
+///
+/// A layout file has one section per line, optionally followed by its
+/// address, with `;` comments:
+///
+/// ```text
+/// text            0x0000
+/// expected_output 0x1000
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub entries: Vec<LayoutEntry>,
+}
+
+impl Default for Layout {
+    /// Instructions at address 0, as the CPU starts executing there.
+    fn default() -> Self {
+        Layout::at(0)
+    }
+}
+
+impl Layout {
+    pub fn parse(text: &str, file: &str) -> Result<Layout, AsmErrors> {
+        let file: Rc<str> = file.into();
+        let mut entries: Vec<LayoutEntry> = Vec::new();
+        let mut errors = Vec::new();
+
+        for (idx, text) in text.lines().enumerate() {
+            let line = SourceLine::new(&file, idx + 1, text);
+            let parts = line.tokens();
+            if parts.is_empty() {
+                continue;
+            }
+            if parts.len() > 2 {
+                errors.push(line.error(&parts[2], "expected a section name and an optional address"));
+                continue;
+            }
+
+            let section = parts[0].text;
+            if !is_symbol_name(section) {
+                errors.push(line.error(&parts[0], format!("invalid section name `{}`", section)));
+                continue;
+            }
+            if entries.iter().any(|entry| entry.section == section) {
+                errors.push(line.error(&parts[0], format!("section `{}` is placed more than once", section)));
+                continue;
+            }
+
+            let address = match parts.get(1) {
+                None => None,
+                Some(token) => match parse_number(token.text) {
+                    Some(addr) if (0..=0xFFFF).contains(&addr) && addr % 2 == 0 => Some(addr as usize),
+                    Some(_) => {
+                        errors.push(line.error(token, "section address must be even and at most 0xffff"));
+                        continue;
+                    }
+                    None => {
+                        errors.push(line.error(token, format!("invalid address `{}`", token.text)));
+                        continue;
+                    }
+                },
+            };
+            entries.push(LayoutEntry { section: section.to_string(), address });
+        }
+
+        if !errors.is_empty() {
+            return Err(AsmErrors(errors));
+        }
+        Ok(Layout { entries })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Layout, AsmErrors> {
+        let path = path.as_ref();
+        let file = path.display().to_string();
+        match fs::read_to_string(path) {
+            Ok(text) => Layout::parse(&text, &file),
+            Err(e) => Err(AsmErrors(vec![AsmError::new(&file, 0, 0, 0, "", format!("cannot read `{}`: {}", file, e))])),
+        }
+    }
+
+    /// A layout that places the instructions at `base`.
+    pub fn at(base: usize) -> Self {
+        Layout { entries: vec![LayoutEntry { section: "text".to_string(), address: Some(base) }] }
+    }
+
+    /// Sections whose address is given outright.
+    pub(super) fn fixed_addresses(&self) -> HashMap<String, usize> {
+        self.entries.iter()
+            .filter_map(|entry| Some((entry.section.clone(), entry.address?)))
+            .collect()
+    }
+}
+
+/// A byte placed in memory, with the line it came from.
+struct Placed {
+    addr: usize,
+    value: u8,
+    object: usize,
+    line: usize,
+    data: bool,
+}
+
+impl Placed {
+    fn what(&self) -> &'static str {
+        if self.data { "data" } else { "instructions" }
+    }
+}
+
+fn address(place: Place, bases: &[usize]) -> Option<usize> {
+    match place {
+        Place::None => None,
+        Place::Section { section, offset } => Some(bases[section] + offset),
+        Place::Absolute { addr } => Some(addr),
+    }
+}
+
+/// The symbols visible to object `idx`: its own, then the globals of all objects.
+fn symbol_view(idx: usize, tables: &[SymbolMap], exports: &HashMap<String, usize>) -> SymbolMap {
+    let mut view: SymbolMap = exports.iter()
+        .filter_map(|(name, &owner)| Some((name.clone(), *tables[owner].get(name)?)))
+        .collect();
+    view.extend(tables[idx].iter().map(|(name, &value)| (name.clone(), value)));
+    view
+}
+
+/// Place the sections of every object, resolve symbols across objects and
+/// encode every line. Returns `None` if any errors were reported.
+pub(super) fn link_objects(objects: &[Object], layout: &Layout, errors: &mut KeyedErrors) -> Option<Assembly> {
+    let first_error = errors.len();
+
+    // Sections named by the layout come first, then the rest in order of use
+    let mut names: Vec<&str> = layout.entries.iter().map(|entry| entry.section.as_str()).collect();
+    for section in objects.iter().flat_map(|object| &object.sections) {
+        if !names.contains(&section.name.as_str()) {
+            names.push(&section.name);
+        }
+    }
+
+    let mut bases: Vec<Vec<usize>> = objects.iter().map(|object| vec![0; object.sections.len()]).collect();
+    let mut cursor = 0;
+    for name in names {
+        // The first object's part of the section starts exactly where the layout says
+        let mut pinned = false;
+        if let Some(addr) = layout.entries.iter().find(|entry| entry.section == name).and_then(|entry| entry.address) {
+            cursor = addr;
+            pinned = true;
+        }
+        for (obj_idx, object) in objects.iter().enumerate() {
+            let sec_idx = match object.sections.iter().position(|section| section.name == name) {
+                Some(sec_idx) => sec_idx,
+                None => continue,
+            };
+            let section = &object.sections[sec_idx];
+            let start = object.lines.iter()
+                .position(|line| matches!(line.place, Place::Section { section, .. } if section == sec_idx));
+            if !cursor.is_multiple_of(section.align) {
+                if pinned {
+                    if let Some(start) = start {
+                        let line = &object.lines[start].source;
+                        errors.push(((obj_idx, start), line.error(&line.tokens()[0], format!(
+                            "section `{}` needs {}-byte alignment but the layout places it at 0x{:x}",
+                            name, section.align, cursor
+                        ))));
+                    }
+                } else {
+                    cursor = cursor.next_multiple_of(section.align);
+                }
+            }
+            bases[obj_idx][sec_idx] = cursor;
+
+            // Sizes in a section assembled for a fixed address may depend on it
+            if let (Some(fixed), Some(start)) = (section.fixed, start) {
+                if fixed != cursor {
+                    let line = &object.lines[start].source;
+                    errors.push(((obj_idx, start), line.error(&line.tokens()[0], format!(
+                        "section `{}` was assembled for address 0x{:x} but the layout places it at 0x{:x}",
+                        name, fixed, cursor
+                    ))));
+                }
+            }
+            cursor += section.size;
+            pinned = false;
+        }
+    }
+
+    // Labels, and where each symbol is defined
+    let mut tables = vec![SymbolMap::new(); objects.len()];
+    let mut definitions: Vec<Vec<(String, SymbolKind, usize)>> = vec![Vec::new(); objects.len()];
+    let mut pending_equs = vec![Vec::new(); objects.len()];
+    for (obj_idx, object) in objects.iter().enumerate() {
+        for (idx, line) in object.lines.iter().enumerate() {
+            let parts = line.source.tokens();
+            if line.failed || parts.is_empty() {
+                continue;
+            }
+            if parts[0].text == ".equ" {
+                definitions[obj_idx].push((line.source.resolve(parts[1].text).to_string(), SymbolKind::Constant, idx));
+                pending_equs[obj_idx].push(idx);
+            } else if let Some(label) = label_definition(&parts) {
+                let kind = if line.data { SymbolKind::Data } else { SymbolKind::Label };
+                let addr = address(line.place, &bases[obj_idx]).unwrap();
+                let label = line.source.resolve(label);
+                tables[obj_idx].insert(label.to_string(), addr as i64);
+                definitions[obj_idx].push((label.to_string(), kind, idx));
+            }
+        }
+    }
+
+    let mut exports: HashMap<String, usize> = HashMap::new();
+    for (obj_idx, object) in objects.iter().enumerate() {
+        for name in &object.globals {
+            let site = |obj: usize| definitions[obj].iter().find(|(symbol, _, _)| symbol == name).map(|(_, _, idx)| *idx);
+            match (exports.get(name), site(obj_idx)) {
+                (Some(&owner), Some(idx)) => {
+                    let line = &object.lines[idx].source;
+                    let mut error = line.error(&line.tokens()[0], format!("global symbol `{}` is defined more than once", name));
+                    if let Some(other) = site(owner) {
+                        let other = &objects[owner].lines[other].source;
+                        error.notes.push(other.error(&other.tokens()[0], "first defined here"));
+                    }
+                    errors.push(((obj_idx, idx), error));
+                }
+                (None, Some(_)) => {
+                    exports.insert(name.clone(), obj_idx);
+                }
+                // Reported when the object was built
+                (_, None) => {}
+            }
+        }
+    }
+
+    // Constants may refer to labels and constants of any object, so resolve
+    // them in rounds until no more make progress
+    loop {
+        let mut progress = false;
+        for obj_idx in 0..objects.len() {
+            let mut view = symbol_view(obj_idx, &tables, &exports);
+            let lines = &objects[obj_idx].lines;
+            pending_equs[obj_idx].retain(|&idx| {
+                let line = &lines[idx].source;
+                let parts = line.tokens();
+                match expr::evaluate(&operand_span(&parts, 2, line), &view, line) {
+                    Ok(value) => {
+                        let name = line.resolve(parts[1].text);
+                        tables[obj_idx].insert(name.to_string(), value);
+                        view.insert(name.to_string(), value);
+                        progress = true;
+                        false
+                    }
+                    Err(_) => true,
+                }
+            });
+        }
+        if !progress {
+            break;
+        }
+    }
+    for (obj_idx, pending) in pending_equs.iter().enumerate() {
+        let view = symbol_view(obj_idx, &tables, &exports);
+        for &idx in pending {
+            let line = &objects[obj_idx].lines[idx].source;
+            let parts = line.tokens();
+            if let Err(e) = expr::evaluate(&operand_span(&parts, 2, line), &view, line) {
+                errors.push(((obj_idx, idx), e));
+            }
+        }
+    }
+
+    // Second pass: encode every line now that all addresses are known
+    let mut placed = Vec::new();
+    let mut listing = Vec::new();
+    // Stack regions set up with `.stack`, by the line that set them up
+    let mut stacks = Vec::new();
+    for (obj_idx, object) in objects.iter().enumerate() {
+        let view = symbol_view(obj_idx, &tables, &exports);
+        for (idx, object_line) in object.lines.iter().enumerate() {
+            let line = &object_line.source;
+            let parts = line.tokens();
+            let addr = address(object_line.place, &bases[obj_idx]);
+            let mut listed = ListingLine {
+                file: line.file.to_string(),
+                line: line.number,
+                address: addr,
+                bytes: Vec::new(),
+                code: false,
+                source: line.text.clone(),
+            };
+
+            let addr = match addr {
+                Some(addr) if !object_line.failed && label_definition(&parts).is_none() => addr,
+                _ => {
+                    listing.push(listed);
+                    continue;
+                }
+            };
+
+            if data::is_directive(parts[0].text) {
+                let ctx = LineContext {
+                    parts: &parts,
+                    line,
+                    symbols: &view,
+                    addr,
+                    scratch: None,
+                    stack: None,
+                    reserved: Some(object_line.size),
+                };
+                let emitted = data::emit(&ctx).and_then(|bytes| {
+                    if bytes.len() != object_line.size {
+                        return Err(line.error(&parts[0], format!(
+                            "`{}` emitted {} bytes but {} were reserved; its counts must be defined before this line",
+                            parts[0].text, bytes.len(), object_line.size
+                        )));
+                    }
+                    Ok(bytes)
+                });
+                match emitted {
+                    Ok(bytes) => listed.bytes = bytes,
+                    Err(e) => errors.push(((obj_idx, idx), e)),
+                }
+            } else if object_line.data {
+                match parse_data_value(&operand_span(&parts, 0, line), &view, line) {
+                    Ok(value) => listed.bytes.extend(value.to_le_bytes()),
+                    Err(e) => errors.push(((obj_idx, idx), e)),
+                }
+            } else {
+                let ctx = LineContext {
+                    parts: &parts,
+                    line,
+                    symbols: &view,
+                    addr,
+                    scratch: object_line.scratch,
+                    stack: object_line.stack,
+                    reserved: Some(object_line.size),
+                };
+                let expanded = parse_instruction(&ctx).and_then(|insts| {
+                    // Labels after this line were placed using the first pass size
+                    let size: usize = insts.iter().map(Instruction::size).sum();
+                    if size != object_line.size {
+                        return Err(line.error(&parts[0], format!(
+                            "`{}` expanded to {} bytes but {} were reserved; operands that change its size must be defined before this line",
+                            parts[0].text, size, object_line.size
+                        )));
+                    }
+                    Ok(insts)
+                });
+                if parts[0].text == ".stack" {
+                    stacks.extend(pseudo::stack_region(&ctx).ok().map(|(_, base, size)| (base as usize, size, obj_idx, idx)));
+                }
+                match expanded {
+                    Ok(insts) => {
+                        listed.code = true;
+                        for inst in insts {
+                            listed.bytes.extend(encode_instruction(inst.clone()).to_le_bytes());
+
+                            // For LOADW, add the immediate value as a second word
+                            if let Instruction::LoadW { imm, .. } = inst {
+                                listed.bytes.extend(imm.to_le_bytes());
+                            }
+                        }
+                    }
+                    Err(e) => errors.push(((obj_idx, idx), e)),
+                }
+            }
+
+            for (offset, &value) in listed.bytes.iter().enumerate() {
+                placed.push(Placed { addr: addr + offset, value, object: obj_idx, line: idx, data: object_line.data });
+            }
+            listing.push(listed);
+        }
+    }
+
+    errors.extend(check_placement(&mut placed, objects));
+    errors.extend(check_stacks(&stacks, &placed, objects));
+    if errors.len() > first_error {
+        return None;
+    }
+
+    let mut symbols = Vec::new();
+    for (obj_idx, defined) in definitions.into_iter().enumerate() {
+        let mut seen = HashSet::new();
+        for (name, kind, _) in defined {
+            if seen.insert(name.clone()) {
+                let value = tables[obj_idx][&name];
+                symbols.push(Symbol { name, value, kind });
+            }
+        }
+    }
+    symbols.sort_by(|a, b| (a.value, &a.name).cmp(&(b.value, &b.name)));
+
+    // Bytes are little-endian halves of words; a word with only one byte
+    // placed keeps zero in the other
+    let mut words = vec![0u16; placed.last().map_or(0, |byte| byte.addr / 2 + 1)];
+    for byte in &placed {
+        words[byte.addr / 2] |= (byte.value as u16) << (8 * (byte.addr % 2));
+    }
+    let mut segments: Vec<Segment> = Vec::new();
+    for byte in &placed {
+        let addr = byte.addr & !1;
+        match segments.last_mut() {
+            Some(segment) if segment.address + segment.words.len() * 2 > addr => {}
+            Some(segment) if segment.address + segment.words.len() * 2 == addr => segment.words.push(words[addr / 2]),
+            _ => segments.push(Segment { address: addr, words: vec![words[addr / 2]] }),
+        }
+    }
+
+    Some(Assembly { words, segments, listing, symbols })
+}
+
+/// Check that every byte fits in memory and that no two lines place a byte
+/// at the same address. Sorts `placed` by address.
+fn check_placement(placed: &mut [Placed], objects: &[Object]) -> KeyedErrors {
+    // Instructions sort first, so data placed over them is what gets reported
+    placed.sort_by_key(|byte| (byte.addr, byte.data, byte.object, byte.line));
+
+    let mut errors = Vec::new();
+    let mut reported = HashSet::new();
+    for (idx, byte) in placed.iter().enumerate() {
+        let line = &objects[byte.object].lines[byte.line].source;
+        let token = line.tokens()[0];
+
+        if byte.addr > 0xFFFF {
+            if reported.insert((byte.object, byte.line)) {
+                let message = format!("{} at address 0x{:x} does not fit in memory", byte.what(), byte.addr);
+                errors.push(((byte.object, byte.line), line.error(&token, message)));
+            }
+            continue;
+        }
+
+        let first = &placed[placed[..idx].partition_point(|other| other.addr < byte.addr)];
+        if first.addr != byte.addr || std::ptr::eq(first, byte) || !reported.insert((byte.object, byte.line)) {
+            continue;
+        }
+        let other = &objects[first.object].lines[first.line].source;
+        let mut error = line.error(&token, format!(
+            "{} at address 0x{:x} overlaps with {}", byte.what(), byte.addr, first.what()
+        ));
+        error.notes.push(other.error(&other.tokens()[0], format!("{} at 0x{:x} placed here", first.what(), byte.addr)));
+        errors.push(((byte.object, byte.line), error));
+    }
+    errors
+}
+
+/// Report bytes placed where a stack grows, which pushes would overwrite.
+fn check_stacks(stacks: &[(usize, usize, usize, usize)], placed: &[Placed], objects: &[Object]) -> KeyedErrors {
+    let mut errors = Vec::new();
+    for &(base, size, object, line) in stacks {
+        let Some(byte) = placed.iter().find(|byte| (base..base + size).contains(&byte.addr)) else { continue };
+        let source = &objects[object].lines[line].source;
+        let mut error = source.error(&source.tokens()[0], format!(
+            "the stack at 0x{:04x}-0x{:04x} overlaps {} at 0x{:x}", base, base + size - 1, byte.what(), byte.addr
+        ));
+        let other = &objects[byte.object].lines[byte.line].source;
+        error.notes.push(other.error(&other.tokens()[0], format!("{} at 0x{:x} placed here", byte.what(), byte.addr)));
+        errors.push(((object, line), error));
+    }
+    errors
+}
+
+/// Link objects into a memory image, placing their sections according to `layout`.
+pub fn link(objects: &[Object], layout: &Layout) -> Result<Assembly, AsmErrors> {
+    let mut errors = Vec::new();
+    match link_objects(objects, layout, &mut errors) {
+        Some(assembly) => Ok(assembly),
+        None => {
+            errors.sort_by_key(|(key, _)| *key);
+            Err(AsmErrors(errors.into_iter().map(|(_, e)| e).collect()))
+        }
+    }
+}
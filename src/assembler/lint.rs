@@ -0,0 +1,450 @@
+use super::ast::Program;
+use super::cfg::{self, CodeInstruction, Values};
+use super::{encode, AssemblerOptions, Assembly, Instruction, SourceLine, SymbolKind};
+use crate::error::{AsmError, AsmErrors};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+/// A mistake the assembler accepts but the linter reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    /// A jump to an odd address, which the CPU fetches misaligned
+    OddJump,
+    /// Execution that can run past the last instruction
+    MissingHlt,
+    /// Instructions no path from the entry point reaches
+    Unreachable,
+    /// A register read before anything was written to it
+    UninitializedRegister,
+    /// Bytes placed in a region the challenge fills in at run time
+    ReservedOverlap,
+    /// A `STORE` whose operands look swapped
+    StoreOrder,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 6] = [
+        Lint::OddJump,
+        Lint::MissingHlt,
+        Lint::Unreachable,
+        Lint::UninitializedRegister,
+        Lint::ReservedOverlap,
+        Lint::StoreOrder,
+    ];
+
+    /// The name used in `lint:` comments and on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::OddJump => "odd-jump",
+            Lint::MissingHlt => "missing-hlt",
+            Lint::Unreachable => "unreachable",
+            Lint::UninitializedRegister => "uninitialized-register",
+            Lint::ReservedOverlap => "reserved-overlap",
+            Lint::StoreOrder => "store-order",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::ALL.into_iter().find(|lint| lint.name() == name)
+    }
+
+    fn default_severity(self) -> Severity {
+        match self {
+            Lint::OddJump => Severity::Deny,
+            _ => Severity::Warn,
+        }
+    }
+}
+
+/// How a lint is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Not reported
+    Allow,
+    Warn,
+    /// Reported as an error, which fails `--lint`
+    Deny,
+}
+
+impl Severity {
+    pub fn from_name(name: &str) -> Option<Severity> {
+        match name {
+            "allow" => Some(Severity::Allow),
+            "warn" => Some(Severity::Warn),
+            "deny" => Some(Severity::Deny),
+            _ => None,
+        }
+    }
+}
+
+/// A range of memory that programs should leave alone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub start: usize,
+    /// Last byte of the region
+    pub end: usize,
+}
+
+/// Which lints are reported and how, for every file. A file can override
+/// this with comments such as `; lint: allow unreachable, store-order`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintConfig {
+    levels: HashMap<Lint, Severity>,
+    /// Regions checked by `reserved-overlap`
+    pub reserved: Vec<Region>,
+}
+
+impl Default for LintConfig {
+    /// Every lint at its default level, with the regions the challenge
+    /// server writes the expected output, circuit state and circuit to.
+    fn default() -> Self {
+        let region = |name: &str, start, end| Region { name: name.to_string(), start, end };
+        LintConfig {
+            levels: HashMap::new(),
+            reserved: vec![
+                region("expected output", 0x1000, 0x1fff),
+                region("circuit state", 0x2000, 0x2fff),
+                region("circuit", 0x3000, 0xffff),
+            ],
+        }
+    }
+}
+
+impl LintConfig {
+    pub fn set(&mut self, lint: Lint, severity: Severity) {
+        self.levels.insert(lint, severity);
+    }
+
+    pub fn severity(&self, lint: Lint) -> Severity {
+        self.levels.get(&lint).copied().unwrap_or(lint.default_severity())
+    }
+}
+
+/// A lint reported against a span of the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub lint: Lint,
+    pub severity: Severity,
+    pub error: AsmError,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self.severity {
+            Severity::Deny => "error",
+            _ => "warning",
+        };
+        self.error.write_labelled(f, &format!("{}[{}]", label, self.lint.name()))
+    }
+}
+
+/// An instruction of the assembled program.
+struct Decoded {
+    code: CodeInstruction,
+    /// Whether it loads the address of a label
+    loads_address: bool,
+}
+
+/// What is known about a register at some point of the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Register {
+    value: Option<u16>,
+    /// Written on every path to this point
+    written: bool,
+    /// Whether it holds an address, if that is known
+    address: Option<bool>,
+}
+
+type State = [Register; 8];
+
+fn join(a: &State, b: &State) -> State {
+    let mut joined = *a;
+    for (reg, other) in joined.iter_mut().zip(b) {
+        reg.value = reg.value.filter(|value| other.value == Some(*value));
+        reg.written &= other.written;
+        reg.address = reg.address.filter(|address| other.address == Some(*address));
+    }
+    joined
+}
+
+/// Registers that `inst` reads.
+fn reads(inst: &Instruction) -> Vec<u8> {
+    match *inst {
+        Instruction::Add { dest, src } | Instruction::Nand { dest, src } => vec![dest, src],
+        Instruction::AddI { dest, .. } => vec![dest],
+        Instruction::Gt { src1, src2, .. } => vec![src1, src2],
+        Instruction::Store { addr, src } => vec![addr, src],
+        Instruction::Load { src, .. } => vec![src],
+        Instruction::Jz { reg, .. } => vec![reg],
+        Instruction::Flag => vec![0, 1, 2, 3],
+        _ => Vec::new(),
+    }
+}
+
+fn values(state: &State) -> Values {
+    state.map(|reg| reg.value)
+}
+
+/// The state after `decoded` runs.
+fn step(state: &State, decoded: &Decoded) -> State {
+    let mut next = *state;
+    let Some((dest, value)) = cfg::write(&decoded.code.instruction, &values(state)) else { return next };
+    let address = match decoded.code.instruction {
+        // An address plus an offset is still an address
+        Instruction::Add { src, .. } => match (state[dest as usize].address, state[src as usize].address) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
+        },
+        Instruction::AddI { .. } => state[dest as usize].address,
+        Instruction::LoadI { .. } | Instruction::LoadW { .. } => Some(decoded.loads_address),
+        Instruction::Load { .. } => None,
+        _ => Some(false),
+    };
+    next[dest as usize] = Register { value, written: true, address };
+    next
+}
+
+/// Whether `line` has a `lint:` comment, and what it sets.
+fn lint_comment(line: &SourceLine) -> Option<Result<(Severity, Vec<Lint>), AsmError>> {
+    let code_len = line.code().len();
+    let comment = line.text[code_len..].get(1..)?.trim();
+    let rest = comment.strip_prefix("lint:")?.trim();
+    let error = |message: String| line.error_at(code_len, line.text.len() - code_len, message);
+
+    let (level, names) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let severity = match Severity::from_name(level) {
+        Some(severity) => severity,
+        None => return Some(Err(error(format!("unknown lint level `{}`, expected allow, warn or deny", level)))),
+    };
+    let mut lints = Vec::new();
+    for name in names.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        match Lint::from_name(name) {
+            Some(lint) => lints.push(lint),
+            None => return Some(Err(error(format!("unknown lint `{}`", name)))),
+        }
+    }
+    Some(Ok((severity, lints)))
+}
+
+struct Linter<'a> {
+    assembly: &'a Assembly,
+    config: &'a LintConfig,
+    /// Levels set by `lint:` comments, by file
+    overrides: HashMap<String, HashMap<Lint, Severity>>,
+    /// The listing lines, for the spans of diagnostics
+    lines: Vec<SourceLine>,
+    diagnostics: Vec<(usize, Diagnostic)>,
+}
+
+impl Linter<'_> {
+    /// Report `lint` on a listing line, at `span` (a column and length) or
+    /// else at its first word.
+    fn report(&mut self, lint: Lint, line: usize, span: Option<(usize, usize)>, message: String) {
+        let listed = &self.assembly.listing[line];
+        let severity = self.overrides.get(&listed.file)
+            .and_then(|levels| levels.get(&lint).copied())
+            .unwrap_or(self.config.severity(lint));
+        if severity == Severity::Allow {
+            return;
+        }
+        let source = &self.lines[line];
+        let (column, len) = span.unwrap_or_else(|| {
+            let first = source.tokens()[0];
+            (first.column, first.text.len())
+        });
+        self.diagnostics.push((line, Diagnostic { lint, severity, error: source.error_at(column, len, message) }));
+    }
+
+    /// The token naming `reg` on a line, if it is written out there.
+    fn register_span(&self, line: usize, reg: u8) -> Option<(usize, usize)> {
+        let name = format!("r{}", reg);
+        self.lines[line].tokens().into_iter().skip(1)
+            .find(|token| token.text.eq_ignore_ascii_case(&name))
+            .map(|token| (token.column, token.text.len()))
+    }
+
+    /// The operands of a line from the `from`th on.
+    fn operands_span(&self, line: usize, from: usize) -> Option<(usize, usize)> {
+        let source = &self.lines[line];
+        let parts = source.tokens();
+        (parts.len() > from).then(|| {
+            let operands = super::operand_span(&parts, from, source);
+            (operands.column, operands.text.len())
+        })
+    }
+
+    /// The jump target of a line: its operands after the registers.
+    fn target_span(&self, line: usize) -> Option<(usize, usize)> {
+        let parts = self.lines[line].tokens();
+        let registers = parts.iter().skip(1)
+            .take_while(|token| super::parse_register(token, &self.lines[line]).is_ok())
+            .count();
+        self.operands_span(line, 1 + registers)
+    }
+
+    fn check_reserved(&mut self) {
+        for (idx, listed) in self.assembly.listing.iter().enumerate() {
+            let start = match listed.address {
+                Some(start) if !listed.bytes.is_empty() => start,
+                _ => continue,
+            };
+            let end = start + listed.bytes.len() - 1;
+            let overlaps: Vec<String> = self.config.reserved.iter()
+                .filter(|region| start <= region.end && region.start <= end)
+                .map(|region| format!("the {} region (0x{:04x}-0x{:04x})", region.name, region.start, region.end))
+                .collect();
+            if !overlaps.is_empty() {
+                let message = format!(
+                    "this line places bytes at 0x{:04x}-0x{:04x}, which overlaps {}; the challenge overwrites it at run time",
+                    start, end, overlaps.join(" and ")
+                );
+                self.report(Lint::ReservedOverlap, idx, None, message);
+            }
+        }
+    }
+
+    fn decode(&self) -> Vec<Decoded> {
+        cfg::decode(self.assembly).into_iter()
+            .map(|code| {
+                let parts = self.lines[code.line].tokens();
+                // `LOADW r0 label` loads an address; `LOADW r0 0x1000` is just a number
+                let loads_address = parts.len() == 3
+                    && ["LOADI", "LOADW", "LI"].contains(&parts[0].text.to_uppercase().as_str())
+                    && self.assembly.symbols.iter().any(|symbol| {
+                        symbol.name == parts[2].text && matches!(symbol.kind, SymbolKind::Label | SymbolKind::Data)
+                    });
+                Decoded { code, loads_address }
+            })
+            .collect()
+    }
+
+    fn check_flow(&mut self) {
+        let decoded = self.decode();
+        let Some(first) = decoded.iter().min_by_key(|decoded| decoded.code.address) else { return };
+        let at: HashMap<usize, usize> = decoded.iter().enumerate().map(|(idx, decoded)| (decoded.code.address, idx)).collect();
+
+        for decoded in &decoded {
+            if let Instruction::Jz { addr, .. } = decoded.code.instruction {
+                if addr % 2 == 1 {
+                    let message = format!("jump target 0x{:02x} is odd, but instructions start at even addresses", addr);
+                    let span = self.target_span(decoded.code.line);
+                    self.report(Lint::OddJump, decoded.code.line, span, message);
+                }
+            }
+        }
+
+        // The CPU starts at address 0, wherever the code was placed
+        let Some(&entry) = at.get(&0) else {
+            let message = format!(
+                "execution starts at address 0, where there is no code, so this code at 0x{:04x} is never reached",
+                first.code.address
+            );
+            self.report(Lint::Unreachable, first.code.line, None, message);
+            return;
+        };
+
+        // After reset every register is zero, but nothing has written it yet
+        let reset = Register { value: Some(0), written: false, address: Some(false) };
+        let clobbered = Register { value: None, written: true, address: None };
+        let mut states: Vec<Option<State>> = vec![None; decoded.len()];
+        states[entry] = Some([reset; 8]);
+        let mut work = vec![entry];
+        while let Some(idx) = work.pop() {
+            let state = states[idx].unwrap();
+            let after = step(&state, &decoded[idx]);
+            // The routine called may leave anything in any register
+            let returned = cfg::return_site(&decoded[idx].code).map(|addr| (addr, [clobbered; 8]));
+            let successors = cfg::successors(&decoded[idx].code, &values(&state)).into_iter().map(|addr| (addr, after));
+            for (addr, after) in successors.chain(returned) {
+                let Some(&next) = at.get(&addr) else { continue };
+                let joined = match &states[next] {
+                    Some(old) => join(old, &after),
+                    None => after,
+                };
+                if states[next] != Some(joined) {
+                    states[next] = Some(joined);
+                    work.push(next);
+                }
+            }
+        }
+
+        let reached_lines: HashSet<usize> = decoded.iter().zip(&states)
+            .filter(|(_, state)| state.is_some())
+            .map(|(decoded, _)| decoded.code.line)
+            .collect();
+        // Unreachable code is reported once for each run of lines
+        let mut in_unreachable_run = false;
+        for (idx, decoded) in decoded.iter().enumerate() {
+            let Some(state) = states[idx] else {
+                if !in_unreachable_run && !reached_lines.contains(&decoded.code.line) {
+                    self.report(Lint::Unreachable, decoded.code.line, None, "this code is never reached".to_string());
+                    in_unreachable_run = true;
+                }
+                continue;
+            };
+            in_unreachable_run = false;
+
+            for reg in reads(&decoded.code.instruction) {
+                if !state[reg as usize].written {
+                    let span = self.register_span(decoded.code.line, reg);
+                    let message = format!("r{} is read before anything is written to it; it is 0 after reset, so write 0 explicitly if that is intended", reg);
+                    if !self.diagnostics.iter().any(|(line, d)| *line == decoded.code.line && d.error.message == message) {
+                        self.report(Lint::UninitializedRegister, decoded.code.line, span, message);
+                    }
+                }
+            }
+
+            if let Instruction::Store { addr, src } = decoded.code.instruction {
+                if state[src as usize].address == Some(true) && state[addr as usize].address == Some(false) {
+                    let message = format!(
+                        "r{} holds an address but is stored as the value; `STORE` takes the address register first, as in `STORE r{} r{}`",
+                        src, src, addr
+                    );
+                    let span = self.operands_span(decoded.code.line, 1);
+                    self.report(Lint::StoreOrder, decoded.code.line, span, message);
+                }
+            }
+
+            let fallthrough = decoded.code.address + decoded.code.instruction.size();
+            if cfg::successors(&decoded.code, &values(&state)).contains(&fallthrough) && !at.contains_key(&fallthrough) {
+                let message = "execution can continue past this instruction, where there is no code; end the program with `HLT` or a jump".to_string();
+                self.report(Lint::MissingHlt, decoded.code.line, None, message);
+            }
+        }
+    }
+}
+
+/// Check an assembled program for mistakes the assembler accepts, such as
+/// jumps to odd addresses or code that is never reached. The program must
+/// assemble; its errors are returned otherwise, along with malformed `lint:`
+/// comments. Diagnostics are in listing order.
+pub fn lint(program: &Program, options: &AssemblerOptions, config: &LintConfig) -> Result<Vec<Diagnostic>, AsmErrors> {
+    let assembly = encode(program, options)?;
+    let mut lines = Vec::new();
+    let mut overrides: HashMap<String, HashMap<Lint, Severity>> = HashMap::new();
+    let mut errors = Vec::new();
+    for listed in &assembly.listing {
+        let line = SourceLine::new(&Rc::from(listed.file.as_str()), listed.line, &listed.source);
+        match lint_comment(&line) {
+            Some(Ok((severity, lints))) => {
+                let levels = overrides.entry(listed.file.clone()).or_default();
+                levels.extend(lints.into_iter().map(|lint| (lint, severity)));
+            }
+            Some(Err(e)) => errors.push(e),
+            None => {}
+        }
+        lines.push(line);
+    }
+    if !errors.is_empty() {
+        return Err(AsmErrors(errors));
+    }
+
+    let mut linter = Linter { assembly: &assembly, config, overrides, lines, diagnostics: Vec::new() };
+    linter.check_reserved();
+    linter.check_flow();
+    linter.diagnostics.sort_by_key(|(line, _)| *line);
+    Ok(linter.diagnostics.into_iter().map(|(_, diagnostic)| diagnostic).collect())
+}
@@ -0,0 +1,141 @@
+use super::Segment;
+use std::fmt;
+
+/// Words shown on each row of a listing; longer expansions continue on
+/// rows of their own.
+const WORDS_PER_ROW: usize = 3;
+
+/// Bytes shown on each row for lines that are not whole words.
+const BYTES_PER_ROW: usize = 4;
+
+/// One source line of an assembled program and what it assembled to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingLine {
+    pub file: String,
+    pub line: usize,
+    /// Byte address of the first byte, or of the label defined on this line
+    pub address: Option<usize>,
+    pub bytes: Vec<u8>,
+    /// Whether the bytes are instructions rather than data
+    pub code: bool,
+    /// The source text, after macro substitution
+    pub source: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    /// A label on an instruction
+    Label,
+    /// A label inside a `.data` block
+    Data,
+    /// A `.equ` constant
+    Constant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: i64,
+    pub kind: SymbolKind,
+}
+
+/// Everything produced by assembling a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    /// The memory image, starting at address 0
+    pub words: Vec<u16>,
+    /// The words that were actually assembled, without the padding between them
+    pub segments: Vec<Segment>,
+    pub listing: Vec<ListingLine>,
+    /// Every symbol, ordered by value and then name
+    pub symbols: Vec<Symbol>,
+}
+
+impl Assembly {
+    /// The listing as text, one row per source line.
+    pub fn listing_text(&self) -> String {
+        self.listing.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    /// The symbol map as text, one symbol per row.
+    pub fn symbol_map_text(&self) -> String {
+        self.symbols.iter().map(|symbol| format!("{}\n", symbol)).collect()
+    }
+
+    /// The image from the first assembled word to the last, with the address
+    /// it starts at.
+    pub fn image(&self) -> (usize, &[u16]) {
+        let start = self.segments.first().map_or(0, |segment| segment.address);
+        (start, &self.words[start / 2..])
+    }
+
+    /// Every assembled word as a `(byte address, word)` pair, leaving out
+    /// the gaps between segments.
+    pub fn patches(&self) -> Vec<(usize, u16)> {
+        self.segments.iter()
+            .flat_map(|segment| segment.words.iter().enumerate().map(move |(idx, &word)| (segment.address + idx * 2, word)))
+            .collect()
+    }
+}
+
+impl ListingLine {
+    /// The bytes as little-endian words, the last one padded with zero.
+    pub fn words(&self) -> Vec<u16> {
+        self.bytes.chunks(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]))
+            .collect()
+    }
+}
+
+/// A row of the listing: words if the line is made of whole words at an
+/// even address, single bytes otherwise.
+fn format_row(bytes: &[u8], as_words: bool) -> String {
+    let items: Vec<String> = if as_words {
+        bytes.chunks(2).map(|pair| format!("{:04x}", u16::from_le_bytes([pair[0], pair[1]]))).collect()
+    } else {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    };
+    items.join(" ")
+}
+
+impl fmt::Display for ListingLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let address = match self.address {
+            Some(address) => format!("{:04x}", address),
+            None => String::new(),
+        };
+        let as_words = self.address.unwrap_or(0).is_multiple_of(2) && self.bytes.len().is_multiple_of(2);
+        let row_len = if as_words { WORDS_PER_ROW * 2 } else { BYTES_PER_ROW };
+        let mut rows = self.bytes.chunks(row_len);
+        let first = format_row(rows.next().unwrap_or(&[]), as_words);
+        let location = format!("{}:{}", self.file, self.line);
+
+        write!(f, "{:<4}  {:<14} {:<24} {}", address, first, location, self.source.trim_end())?;
+        for (idx, row) in rows.enumerate() {
+            let address = self.address.unwrap_or(0) + (idx + 1) * row_len;
+            write!(f, "\n{:04x}  {}", address, format_row(row, as_words))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SymbolKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            SymbolKind::Label => "label",
+            SymbolKind::Data => "data",
+            SymbolKind::Constant => "constant",
+        })
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Constants may be negative or wider than an address
+        if (0..=0xFFFF).contains(&self.value) {
+            write!(f, "{:04x}  {:<8}  {}", self.value, self.kind, self.name)
+        } else {
+            write!(f, "{:<4}  {:<8}  {}", self.value, self.kind, self.name)
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use super::conditional::Conditionals;
+use super::{data, operand_span, SourceLine};
+use crate::error::AsmError;
+use std::collections::HashMap;
+
+/// Macros may invoke other macros, but not without bound.
+const MAX_DEPTH: usize = 64;
+
+struct Macro {
+    name: String,
+    /// Parameter names with their default values
+    params: Vec<(String, Option<String>)>,
+    body: Vec<SourceLine>,
+}
+
+struct Preprocessor {
+    /// Macros by upper-case name, since they are invoked like mnemonics
+    macros: HashMap<String, Macro>,
+    /// Number of expansions so far, substituted for `\@`
+    expansions: usize,
+    conditionals: Conditionals,
+    output: Vec<SourceLine>,
+    errors: Vec<AsmError>,
+}
+
+/// Split `text` wherever `is_separator` matches outside quoted literals.
+fn split_unquoted(text: &str, is_separator: impl Fn(char) -> bool) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    while let Some(c) = text[pos..].chars().next() {
+        if c == '"' || c == '\'' {
+            pos += data::quoted_len(&text[pos..]).unwrap_or(text.len() - pos);
+            continue;
+        }
+        if is_separator(c) {
+            items.push(&text[start..pos]);
+            start = pos + c.len_utf8();
+        }
+        pos += c.len_utf8();
+    }
+    items.push(&text[start..]);
+    items
+}
+
+/// Split a parameter or argument list on commas if it has any, otherwise on
+/// whitespace. Commas and spaces inside quoted literals do not split.
+fn split_list(text: &str) -> Vec<String> {
+    let commas = split_unquoted(text, |c| c == ',');
+    if commas.len() > 1 {
+        commas.into_iter().map(|item| item.trim().to_string()).collect()
+    } else {
+        split_unquoted(text, char::is_whitespace).into_iter()
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+fn is_param_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Replace `\name` with the argument for `name` and `\@` with the expansion
+/// number, which makes labels like `loop\@` unique to each expansion.
+/// Quoted literals are copied as they are, so their escapes are kept.
+fn substitute(line: &SourceLine, args: &HashMap<String, String>, expansion: usize) -> Result<String, AsmError> {
+    let text = &line.text;
+    let mut result = String::new();
+    let mut pos = 0;
+
+    while let Some(offset) = text[pos..].find(['\\', '"', '\'']) {
+        let start = pos + offset;
+        result.push_str(&text[pos..start]);
+        let rest = &text[start + 1..];
+
+        if !text[start..].starts_with('\\') {
+            let len = data::quoted_len(&text[start..]).unwrap_or(text.len() - start);
+            result.push_str(&text[start..start + len]);
+            pos = start + len;
+            continue;
+        }
+
+        if rest.starts_with('@') {
+            result.push_str(&expansion.to_string());
+            pos = start + 2;
+            continue;
+        }
+
+        let len = rest.find(|c: char| !is_param_char(c)).unwrap_or(rest.len());
+        let name = &rest[..len];
+        match args.get(name) {
+            Some(value) => result.push_str(value),
+            None if name.is_empty() => result.push('\\'),
+            None => return Err(line.error_at(start, len + 1, format!("unknown macro parameter `\\{}`", name))),
+        }
+        pos = start + 1 + len;
+    }
+
+    result.push_str(&text[pos..]);
+    Ok(result)
+}
+
+impl Preprocessor {
+    /// Collect a `.macro` definition starting at `lines[start]`, returning the
+    /// index of the first line after its `.endm`.
+    fn define(&mut self, lines: &mut [Option<SourceLine>], start: usize) -> usize {
+        let header = lines[start].take().unwrap();
+        let parts = header.tokens();
+
+        let mut end = start + 1;
+        let mut body = Vec::new();
+        let mut terminated = false;
+        while end < lines.len() {
+            let line = lines[end].take().unwrap();
+            end += 1;
+            match line.tokens().first().map(|token| token.text) {
+                Some(".endm") => {
+                    terminated = true;
+                    break;
+                }
+                Some(".macro") => {
+                    let token = line.tokens()[0];
+                    self.errors.push(line.error(&token, "nested macro definitions are not supported"));
+                }
+                _ => body.push(line),
+            }
+        }
+
+        if !terminated {
+            self.errors.push(header.error(&parts[0], "unterminated `.macro`, expected `.endm`"));
+        }
+        if parts.len() < 2 {
+            self.errors.push(header.error(&parts[0], ".macro directive requires a name"));
+            return end;
+        }
+
+        let name = parts[1].text.to_string();
+        let mut params = Vec::new();
+        if parts.len() > 2 {
+            for param in split_list(operand_span(&parts, 2, &header).text) {
+                let (param_name, default) = match param.split_once('=') {
+                    Some((param_name, default)) => (param_name.trim().to_string(), Some(default.trim().to_string())),
+                    None => (param, None),
+                };
+                if param_name.is_empty() || !param_name.chars().all(is_param_char) {
+                    self.errors.push(header.error(&parts[1], format!("invalid parameter name `{}` in macro `{}`", param_name, name)));
+                }
+                params.push((param_name, default));
+            }
+        }
+
+        if self.macros.contains_key(&name.to_uppercase()) {
+            self.errors.push(header.error(&parts[1], format!("macro `{}` is already defined", name)));
+            return end;
+        }
+        self.macros.insert(name.to_uppercase(), Macro { name, params, body });
+        end
+    }
+
+    /// Bind the arguments of a macro call to the macro's parameters.
+    fn bind_arguments(call: &SourceLine, mac: &Macro) -> Result<HashMap<String, String>, AsmError> {
+        let parts = call.tokens();
+        let args = if parts.len() > 1 { split_list(operand_span(&parts, 1, call).text) } else { Vec::new() };
+        let mut bound = HashMap::new();
+        let mut positional = 0;
+
+        for arg in args {
+            // `name=value` binds by name if `name` is a parameter
+            if let Some((name, value)) = arg.split_once('=') {
+                if mac.params.iter().any(|(param, _)| param == name.trim()) {
+                    bound.insert(name.trim().to_string(), value.trim().to_string());
+                    continue;
+                }
+            }
+            match mac.params.get(positional) {
+                Some((param, _)) => {
+                    bound.insert(param.clone(), arg);
+                }
+                None => {
+                    return Err(call.error(&parts[0], format!(
+                        "macro `{}` takes {} arguments but more were given", mac.name, mac.params.len()
+                    )));
+                }
+            }
+            positional += 1;
+        }
+
+        for (param, default) in &mac.params {
+            if bound.contains_key(param) {
+                continue;
+            }
+            match default {
+                Some(default) => {
+                    bound.insert(param.clone(), default.clone());
+                }
+                None => {
+                    return Err(call.error(&parts[0], format!("missing argument `{}` for macro `{}`", param, mac.name)));
+                }
+            }
+        }
+        Ok(bound)
+    }
+
+    /// Emit a line, expanding it first if it invokes a macro. Lines skipped
+    /// by conditional assembly are dropped here, including those of
+    /// expansions, so macros can use `.if` on their arguments.
+    fn process(&mut self, line: SourceLine, depth: usize) {
+        if !self.conditionals.keep(&line, &mut self.errors) {
+            return;
+        }
+        // Includes are read before macros are expanded, with the conditions
+        // known then, so one that is only active now was never read
+        if let Some(token) = line.tokens().first().filter(|token| token.text == ".include") {
+            let message = "this `.include` was skipped when files were read; \
+                           conditions around an include cannot depend on macro expansions";
+            self.errors.push(line.error(token, message));
+            return;
+        }
+        let mnemonic = match line.tokens().first() {
+            Some(token) => token.text.to_uppercase(),
+            None => {
+                self.output.push(line);
+                return;
+            }
+        };
+        let mac = match self.macros.get(&mnemonic) {
+            Some(mac) => mac,
+            None => {
+                self.output.push(line);
+                return;
+            }
+        };
+
+        let call = line.tokens()[0];
+        if depth >= MAX_DEPTH {
+            let message = format!("macro `{}` nested more than {} levels deep", mac.name, MAX_DEPTH);
+            self.errors.push(line.error(&call, message));
+            return;
+        }
+        let args = match Self::bind_arguments(&line, mac) {
+            Ok(args) => args,
+            Err(e) => {
+                self.errors.push(e);
+                return;
+            }
+        };
+
+        // Lines of the expansion are reported at their place in the macro
+        // body, with a note for this call site and any calls around it
+        let mut notes = vec![AsmError::new(
+            &line.file, line.number, call.column + 1, call.text.len(), &line.text,
+            format!("in this expansion of macro `{}`", mac.name),
+        )];
+        notes.extend(line.notes.iter().cloned());
+
+        self.expansions += 1;
+        let mut expanded = Vec::new();
+        for body_line in &mac.body {
+            match substitute(body_line, &args, self.expansions) {
+                Ok(text) => expanded.push(SourceLine {
+                    file: body_line.file.clone(),
+                    number: body_line.number,
+                    text,
+                    notes: notes.clone(),
+                    aliases: Vec::new(),
+                }),
+                Err(mut e) => {
+                    e.notes = notes.clone();
+                    self.errors.push(e);
+                }
+            }
+        }
+
+        for expanded_line in expanded {
+            self.process(expanded_line, depth + 1);
+        }
+    }
+}
+
+/// Collect `.macro`/`.endm` definitions and replace every invocation with the
+/// macro body, with `\param` references substituted. Conditional assembly is
+/// resolved at the same time, so macros can be defined conditionally.
+pub(super) fn expand_macros(lines: Vec<SourceLine>, errors: &mut Vec<AsmError>) -> Vec<SourceLine> {
+    let mut pre = Preprocessor {
+        macros: HashMap::new(),
+        expansions: 0,
+        conditionals: Conditionals::new(),
+        output: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    let mut lines: Vec<Option<SourceLine>> = lines.into_iter().map(Some).collect();
+    let mut idx = 0;
+    while idx < lines.len() {
+        let first = lines[idx].as_ref().unwrap().tokens().first().map(|token| token.text.to_string());
+        match first.as_deref() {
+            Some(".macro") if pre.conditionals.active() => {
+                idx = pre.define(&mut lines, idx);
+                continue;
+            }
+            Some(".endm") if pre.conditionals.active() => {
+                let line = lines[idx].as_ref().unwrap();
+                pre.errors.push(line.error(&line.tokens()[0], "`.endm` without a matching `.macro`"));
+            }
+            _ => {
+                let line = lines[idx].take().unwrap();
+                pre.process(line, 0);
+            }
+        }
+        idx += 1;
+    }
+
+    pre.conditionals.finish(&mut pre.errors);
+    errors.extend(pre.errors);
+    pre.output
+}
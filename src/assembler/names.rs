@@ -0,0 +1,164 @@
+use super::data::quoted_len;
+use super::structured;
+use super::{is_symbol_name, label_definition, parse_register, SourceLine};
+use crate::error::AsmError;
+use std::collections::HashMap;
+
+/// A numeric label reference, `1b` or `1f`, split into its number and direction.
+pub(super) fn numeric_reference(word: &str) -> Option<(&str, bool)> {
+    let (digits, forward) = match word.as_bytes().last()? {
+        b'b' => (&word[..word.len() - 1], false),
+        b'f' => (&word[..word.len() - 1], true),
+        _ => return None,
+    };
+    if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some((digits, forward))
+}
+
+/// The name numeric label `number` gets the `nth` time it is defined.
+fn numeric_name(number: &str, nth: usize) -> String {
+    format!("{}@{}", number, nth)
+}
+
+/// A `1f` reference waiting for its label.
+struct Forward {
+    number: String,
+    nth: usize,
+    idx: usize,
+    error: AsmError,
+}
+
+/// Gives local and numeric labels the names they are stored under, and
+/// register aliases the registers they stand for, as lines are read in order.
+///
+/// A local label such as `.loop` belongs to the last global label before
+/// it and is stored as `global.loop`, which other scopes may also use to
+/// refer to it. A numeric label such as `1:` may be defined any number of
+/// times; `1b` refers to the last definition before the reference and `1f`
+/// to the next one after it. `.alias sp r7` makes `sp` name `r7` from there
+/// on, until it is aliased again.
+pub(super) struct Names {
+    global: Option<String>,
+    /// How many times each numeric label has been defined so far
+    numeric: HashMap<String, usize>,
+    forward: Vec<Forward>,
+    /// Register aliases and the register each names
+    registers: HashMap<String, String>,
+}
+
+impl Names {
+    pub(super) fn new() -> Self {
+        Names { global: None, numeric: HashMap::new(), forward: Vec::new(), registers: HashMap::new() }
+    }
+
+    fn alias(&mut self, line: &SourceLine) -> Result<(), AsmError> {
+        let parts = line.tokens();
+        if parts.len() != 3 {
+            return Err(line.error(&parts[0], "`.alias` expects a name and a register"));
+        }
+        let name = parts[1].text;
+        if !is_symbol_name(name) || parse_register(&parts[1], line).is_ok() {
+            return Err(line.error(&parts[1], format!("invalid alias name `{}`", name)));
+        }
+        // Aliases of aliases name the same register
+        let reg = match self.registers.get(parts[2].text) {
+            Some(reg) => reg.clone(),
+            None => format!("r{}", parse_register(&parts[2], line)?),
+        };
+        self.registers.insert(name.to_string(), reg);
+        Ok(())
+    }
+
+    fn local_name(&self, name: &str, line: &SourceLine, column: usize) -> Result<String, AsmError> {
+        match &self.global {
+            Some(global) => Ok(format!("{}{}", global, name)),
+            None => Err(line.error_at(column, name.len(), format!(
+                "local label `{}` is used before any global label", name
+            ))),
+        }
+    }
+
+    /// The stored names of the local and numeric labels line `idx` defines
+    /// or refers to, as `(written, stored)` pairs. `value` is whether the
+    /// line is a bare value in a `.data` block, whose first word is no
+    /// mnemonic and may refer to labels too.
+    pub(super) fn resolve(&mut self, idx: usize, line: &SourceLine, value: bool) -> Result<Vec<(String, String)>, AsmError> {
+        let parts = line.tokens();
+        if let Some(label) = label_definition(&parts) {
+            if label.starts_with('.') {
+                return Ok(vec![(label.to_string(), self.local_name(label, line, parts[0].column)?)]);
+            }
+            if label.bytes().all(|c| c.is_ascii_digit()) {
+                let count = self.numeric.entry(label.to_string()).or_insert(0);
+                *count += 1;
+                return Ok(vec![(label.to_string(), numeric_name(label, *count))]);
+            }
+            if !structured::is_generated(label) {
+                self.global = Some(label.to_string());
+            }
+            return Ok(Vec::new());
+        }
+
+        // Section names may start with `.` too
+        match parts.first().map(|token| token.text) {
+            Some(".section" | ".global") => return Ok(Vec::new()),
+            Some(".alias") => return self.alias(line).map(|_| Vec::new()),
+            _ => {}
+        }
+
+        // Every word after the mnemonic or directive, outside of quotes
+        let code = line.code();
+        let mut aliases = Vec::new();
+        let mut pos = match value {
+            true => 0,
+            false => parts.first().map_or(0, |token| token.column + token.text.len()),
+        };
+        while pos < code.len() {
+            let rest = &code[pos..];
+            let c = rest.chars().next().unwrap();
+            if c == '"' || c == '\'' {
+                pos += quoted_len(rest).unwrap_or(rest.len());
+                continue;
+            }
+            if !(c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+                pos += c.len_utf8();
+                continue;
+            }
+
+            let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.')).unwrap_or(rest.len());
+            let word = &rest[..len];
+            if let Some(reg) = self.registers.get(word) {
+                aliases.push((word.to_string(), reg.clone()));
+            } else if word.len() > 1 && word.starts_with('.') && is_symbol_name(word) {
+                aliases.push((word.to_string(), self.local_name(word, line, pos)?));
+            } else if let Some((number, forward)) = numeric_reference(word) {
+                let defined = self.numeric.get(number).copied().unwrap_or(0);
+                if forward {
+                    self.forward.push(Forward {
+                        number: number.to_string(),
+                        nth: defined + 1,
+                        idx,
+                        error: line.error_at(pos, len, format!("no numeric label `{}:` after this line", number)),
+                    });
+                    aliases.push((word.to_string(), numeric_name(number, defined + 1)));
+                } else if defined == 0 {
+                    return Err(line.error_at(pos, len, format!("no numeric label `{}:` before this line", number)));
+                } else {
+                    aliases.push((word.to_string(), numeric_name(number, defined)));
+                }
+            }
+            pos += len;
+        }
+        Ok(aliases)
+    }
+
+    /// The `1f` references that no later label satisfied, by line index.
+    pub(super) fn finish(self) -> Vec<(usize, AsmError)> {
+        self.forward.into_iter()
+            .filter(|forward| self.numeric.get(&forward.number).copied().unwrap_or(0) < forward.nth)
+            .map(|forward| (forward.idx, forward.error))
+            .collect()
+    }
+}
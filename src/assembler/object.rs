@@ -0,0 +1,399 @@
+use super::names::Names;
+use super::{
+    data, expect_operands, expr, is_symbol_name, label_definition, operand_span, parse_data_address, parse_instruction,
+    parse_register, Instruction, LineContext, SourceLine, SymbolMap, Token,
+};
+use crate::error::AsmError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Where the words or label of a line end up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) enum Place {
+    /// Directives, comments and blank lines
+    None,
+    /// An offset into one of the object's sections
+    Section { section: usize, offset: usize },
+    /// A fixed address inside a `.data ADDR` block
+    Absolute { addr: usize },
+}
+
+/// A source line after the first pass, with everything the second pass
+/// needs to encode it once its section has been placed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct ObjectLine {
+    pub(super) source: SourceLine,
+    pub(super) place: Place,
+    /// Whether the line is data, or a label naming data
+    pub(super) data: bool,
+    /// Bytes reserved for the line
+    pub(super) size: usize,
+    /// Scratch register in effect for pseudo-instructions on this line
+    pub(super) scratch: Option<u8>,
+    /// Stack pointer in effect for pseudo-instructions on this line
+    #[serde(default)]
+    pub(super) stack: Option<u8>,
+    /// Set if the first pass already reported an error for this line
+    #[serde(skip)]
+    pub(super) failed: bool,
+}
+
+/// A named run of bytes whose address is decided by the linker.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Section {
+    pub name: String,
+    /// Size in bytes
+    pub size: usize,
+    /// The linker starts the section at a multiple of this, so `.align`
+    /// inside it holds wherever it is placed
+    pub align: usize,
+    /// Address the first pass assumed for the section, which the linker must
+    /// keep because instruction sizes may depend on it
+    pub fixed: Option<usize>,
+}
+
+/// A program that has been sized but not placed: the output of the first pass.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Object {
+    /// File the object was assembled from
+    pub name: String,
+    pub sections: Vec<Section>,
+    /// Symbols other objects may refer to, declared with `.global`
+    pub globals: Vec<String>,
+    pub(super) lines: Vec<ObjectLine>,
+}
+
+impl Object {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn from_json(text: &str) -> Result<Object, serde_json::Error> {
+        serde_json::from_str(text)
+    }
+}
+
+struct Builder<'a> {
+    fixed: &'a HashMap<String, usize>,
+    sections: Vec<Section>,
+    current: usize,
+    /// Next address of the current `.data ADDR` block, if in one
+    absolute: Option<usize>,
+    data: bool,
+    /// Symbols whose value is already known, for sizing
+    symbols: SymbolMap,
+    /// Every label and constant name, known or not, with the line defining it
+    defined: HashMap<String, usize>,
+    scratch: Option<u8>,
+    stack: Option<u8>,
+}
+
+impl Builder<'_> {
+    fn enter(&mut self, name: &str) {
+        self.current = match self.sections.iter().position(|section| section.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.sections.push(Section { name: name.to_string(), size: 0, align: 2, fixed: self.fixed.get(name).copied() });
+                self.sections.len() - 1
+            }
+        };
+        self.absolute = None;
+        self.data = false;
+    }
+
+    fn here(&self) -> Place {
+        match self.absolute {
+            Some(addr) => Place::Absolute { addr },
+            None => Place::Section { section: self.current, offset: self.sections[self.current].size },
+        }
+    }
+
+    /// The address of `place` if the first pass can know it.
+    fn known_address(&self, place: Place) -> Option<usize> {
+        match place {
+            Place::None => None,
+            Place::Section { section, offset } => self.sections[section].fixed.map(|base| base + offset),
+            Place::Absolute { addr } => Some(addr),
+        }
+    }
+
+    /// Record the definition of `name` on line `idx`, which must be its only one.
+    fn define(&mut self, name: &str, idx: usize, token: &Token, line: &SourceLine, lines: &[ObjectLine]) -> Result<(), AsmError> {
+        if let Some(&first) = self.defined.get(name) {
+            let mut error = line.error(token, format!("`{}` is defined more than once", name));
+            let other = &lines[first].source;
+            error.notes.push(other.error(&other.tokens()[0], "first defined here"));
+            return Err(error);
+        }
+        self.defined.insert(name.to_string(), idx);
+        Ok(())
+    }
+
+    /// Move the location counter forward to `addr`, leaving a gap that no
+    /// words are placed in.
+    fn org(&mut self, addr: usize) -> Result<(), String> {
+        if !addr.is_multiple_of(2) {
+            return Err(format!("`.org` address 0x{:x} is not aligned to 2 bytes", addr));
+        }
+        let current = match self.known_address(self.here()) {
+            Some(current) => current,
+            None => {
+                let name = &self.sections[self.current].name;
+                return Err(format!("`.org` needs the address of section `{}`; give it one in the layout", name));
+            }
+        };
+        if addr < current {
+            return Err(format!("`.org` cannot move back from 0x{:x} to 0x{:x}", current, addr));
+        }
+        self.advance(addr - current);
+        Ok(())
+    }
+
+    /// The address `.align` works from: the real one if known, otherwise the
+    /// offset into the section, which the linker keeps aligned.
+    fn align_base(&self) -> usize {
+        let place = self.here();
+        match (self.known_address(place), place) {
+            (Some(addr), _) => addr,
+            (None, Place::Section { offset, .. }) => offset,
+            (None, _) => unreachable!(),
+        }
+    }
+
+    /// Words can only be placed at even addresses. Sections always start at
+    /// one, so the offset tells as well as the address.
+    fn check_even(&self, what: &str, token: &Token, line: &SourceLine) -> Result<(), AsmError> {
+        if self.align_base().is_multiple_of(2) {
+            return Ok(());
+        }
+        Err(line.error(token, format!("{} must start at an even address; add `.align 2` before this line", what)))
+    }
+
+    fn advance(&mut self, size: usize) {
+        match &mut self.absolute {
+            Some(addr) => *addr += size,
+            None => self.sections[self.current].size += size,
+        }
+    }
+}
+
+/// Whether `name` is a directive the first pass handles, even in a `.data` block.
+fn is_directive(name: &str) -> bool {
+    matches!(name, ".equ" | ".data" | ".text" | ".org" | ".section" | ".global" | ".alias" | ".scratch" | ".stack")
+        || data::is_directive(name)
+}
+
+/// First pass: place every line in a section, size its instructions and
+/// collect the labels. Sections with an address in `fixed` are assumed to
+/// be placed there, so their labels can already be used for sizing; the
+/// rest are only resolved by the linker.
+pub(super) fn build(name: &str, lines: Vec<SourceLine>, fixed: &HashMap<String, usize>, errors: &mut Vec<(usize, AsmError)>) -> Object {
+    let mut builder = Builder {
+        fixed,
+        sections: Vec::new(),
+        current: 0,
+        absolute: None,
+        data: false,
+        symbols: SymbolMap::new(),
+        defined: HashMap::new(),
+        scratch: None,
+        stack: None,
+    };
+    let mut scopes = Names::new();
+    builder.enter("text");
+
+    let mut globals: Vec<(String, usize)> = Vec::new();
+    let mut output = Vec::new();
+    for (idx, mut line) in lines.into_iter().enumerate() {
+        let mut error = None;
+        let value = builder.data && line.tokens().first().is_some_and(|token| !is_directive(token.text));
+        match scopes.resolve(idx, &line, value) {
+            Ok(aliases) => line.aliases = aliases,
+            Err(e) => error = Some(e),
+        }
+        let parts = line.tokens();
+        let mut place = Place::None;
+        let mut data = false;
+        let mut size = 0;
+        let mut scratch = None;
+        let mut stack = None;
+
+        match parts.first().map(|token| token.text) {
+            None => {}
+            _ if error.is_some() => {}
+            // Constants that refer to labels the linker places are resolved then
+            Some(".equ") => {
+                if parts.len() < 3 {
+                    error = Some(line.error(&parts[0], ".equ directive requires a name and a value"));
+                } else if !is_symbol_name(parts[1].text) {
+                    error = Some(line.error(&parts[1], format!("invalid symbol name `{}`", parts[1].text)));
+                } else {
+                    let name = line.resolve(parts[1].text);
+                    error = builder.define(name, idx, &parts[1], &line, &output).err();
+                    if let Ok(value) = expr::evaluate(&operand_span(&parts, 2, &line), &builder.symbols, &line) {
+                        builder.symbols.insert(name.to_string(), value);
+                    }
+                }
+            }
+            // A bare `.data` holds data in the current section, `.data ADDR` at a fixed address
+            Some(".data") => {
+                builder.data = true;
+                match parts.len() {
+                    1 => builder.absolute = None,
+                    2 => match parse_data_address(&parts[1], &line) {
+                        Ok(addr) => builder.absolute = Some(addr),
+                        Err(e) => error = Some(e),
+                    },
+                    _ => error = Some(line.error(&parts[0], ".data directive takes at most one address")),
+                }
+            }
+            Some(".text") => builder.enter("text"),
+            Some(".org") => {
+                if parts.len() < 2 {
+                    error = Some(line.error(&parts[0], ".org directive requires an address"));
+                } else {
+                    let operand = operand_span(&parts, 1, &line);
+                    match expr::evaluate(&operand, &builder.symbols, &line) {
+                        Ok(addr) if (0..=0xFFFF).contains(&addr) => {
+                            if let Err(message) = builder.org(addr as usize) {
+                                error = Some(line.error(&operand, message));
+                            }
+                        }
+                        Ok(addr) => error = Some(line.error(&operand, format!("`.org` address {} is outside memory", addr))),
+                        Err(e) => error = Some(e),
+                    }
+                }
+            }
+            Some(".section") => {
+                if let Err(e) = expect_operands(&parts, 1, "a section name", &line) {
+                    error = Some(e);
+                } else if !is_symbol_name(parts[1].text) {
+                    error = Some(line.error(&parts[1], format!("invalid section name `{}`", parts[1].text)));
+                } else {
+                    builder.enter(parts[1].text);
+                }
+            }
+            Some(".global") => {
+                if parts.len() < 2 {
+                    error = Some(line.error(&parts[0], ".global directive requires at least one symbol name"));
+                }
+                for token in &parts[1..] {
+                    globals.push((token.text.to_string(), idx));
+                }
+            }
+            // Checked and recorded when names were resolved
+            Some(".alias") => {}
+            Some(".scratch") => {
+                let reg = expect_operands(&parts, 1, "a register", &line)
+                    .and_then(|_| parse_register(&parts[1], &line));
+                match reg {
+                    Ok(reg) => builder.scratch = Some(reg),
+                    Err(e) => error = Some(e),
+                }
+            }
+            // Data directives work in any section or `.data` block
+            Some(name) if data::is_directive(name) => {
+                place = builder.here();
+                data = true;
+                if data::is_word_directive(name) {
+                    error = builder.check_even(&format!("`{}`", name), &parts[0], &line).err();
+                }
+                let ctx = LineContext {
+                    parts: &parts,
+                    line: &line,
+                    symbols: &builder.symbols,
+                    addr: builder.align_base(),
+                    scratch: None,
+                    stack: None,
+                    reserved: None,
+                };
+                if error.is_none() {
+                    match data::emit(&ctx) {
+                        Ok(bytes) => size = bytes.len(),
+                        Err(e) => error = Some(e),
+                    }
+                }
+                if name == ".align" && error.is_none() && builder.absolute.is_none() {
+                    let operands = data::split_operands(&operand_span(&parts, 1, &line));
+                    let align = expr::evaluate(&operands[0], &builder.symbols, &line).unwrap() as usize;
+                    let section = &mut builder.sections[builder.current];
+                    section.align = section.align.max(align);
+                }
+            }
+            // Labels name the current data or instruction address
+            _ if label_definition(&parts).is_some() => {
+                let label = line.resolve(label_definition(&parts).unwrap());
+                place = builder.here();
+                data = builder.data;
+                if let Some(addr) = builder.known_address(place) {
+                    builder.symbols.insert(label.to_string(), addr as i64);
+                }
+                error = builder.define(label, idx, &parts[0], &line, &output).err();
+            }
+            Some(".stack") if builder.data => {
+                error = Some(line.error(&parts[0], "`.stack` sets the stack pointer with an instruction, so it cannot be in a data block"));
+            }
+            _ if builder.data => {
+                place = builder.here();
+                data = true;
+                size = 2; // Each data value is 2 bytes
+                error = builder.check_even("data values", &parts[0], &line).err();
+            }
+            // Size the instruction, pseudo-instructions may expand to several
+            _ => {
+                place = builder.here();
+                scratch = builder.scratch;
+                stack = builder.stack;
+                let ctx = LineContext {
+                    parts: &parts,
+                    line: &line,
+                    symbols: &builder.symbols,
+                    addr: builder.known_address(place).unwrap_or(0),
+                    scratch,
+                    stack,
+                    reserved: None,
+                };
+                size = match builder.check_even("instructions", &parts[0], &line).and_then(|_| parse_instruction(&ctx)) {
+                    Ok(insts) => insts.iter().map(Instruction::size).sum(),
+                    Err(e) => {
+                        error = Some(e);
+                        2
+                    }
+                };
+                // `.stack` is encoded like an instruction and applies to the lines after it
+                if parts[0].text == ".stack" && error.is_none() {
+                    builder.stack = parse_register(&parts[1], &line).ok();
+                }
+            }
+        }
+
+        builder.advance(size);
+        let failed = error.is_some();
+        if let Some(e) = error {
+            errors.push((idx, e));
+        }
+        drop(parts);
+        output.push(ObjectLine { source: line, place, data, size, scratch, stack, failed });
+    }
+
+    for (symbol, idx) in &globals {
+        if !builder.defined.contains_key(symbol) {
+            let line = &output[*idx].source;
+            let token = line.tokens().into_iter().find(|token| token.text.contains(symbol.as_str())).unwrap();
+            errors.push((*idx, line.error(&token, format!("global symbol `{}` is not defined in this file", symbol))));
+        }
+    }
+
+    for (idx, e) in scopes.finish() {
+        output[idx].failed = true;
+        errors.push((idx, e));
+    }
+
+    let mut names: Vec<String> = Vec::new();
+    for (symbol, _) in globals {
+        if !names.contains(&symbol) {
+            names.push(symbol);
+        }
+    }
+    Object { name: name.to_string(), sections: builder.sections, globals: names, lines: output }
+}
@@ -0,0 +1,470 @@
+use super::ast::{parse_named, Program, Statement, StatementKind};
+use super::cfg::{self, CodeInstruction, Values, CYCLES_PER_INSTRUCTION};
+use super::format::render;
+use super::names::numeric_reference;
+use super::{
+    encode, expr, is_symbol_name, label_definition, operand_span, parse_register, AssemblerOptions, Assembly,
+    Instruction, SourceLine, SymbolKind, SymbolMap,
+};
+use crate::error::AsmErrors;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// Rounds of rewriting, since one rewrite can make another possible.
+const MAX_ROUNDS: usize = 8;
+
+/// A change the optimizer made to one line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rewrite {
+    pub line: usize,
+    /// The code of the line, without its comment
+    pub before: String,
+    /// The code that replaced it, or `None` if the line was removed
+    pub after: Option<String>,
+    pub reason: &'static str,
+}
+
+/// The result of [`optimize`].
+#[derive(Debug, Clone)]
+pub struct Optimization {
+    /// The optimized program, with the same lines as the original
+    pub program: Program,
+    pub rewrites: Vec<Rewrite>,
+    /// Bytes of code saved
+    pub bytes_saved: usize,
+    /// Clock cycles saved each time every rewritten line runs once
+    pub cycles_saved: usize,
+}
+
+/// Instructions that came from one source line, run one after another.
+struct Group<'a> {
+    code: &'a [CodeInstruction],
+    /// Statement line this group can be rewritten on
+    line: Option<usize>,
+    mnemonic: String,
+    /// The text of the value operand of a load or a jump
+    operand: Option<String>,
+    /// Whether every operand is a register or a number, so the values the
+    /// line writes do not depend on where anything is placed
+    fixed: bool,
+    /// Whether execution can arrive at the group from elsewhere
+    entry: bool,
+}
+
+impl Group<'_> {
+    fn instructions(&self) -> Vec<Instruction> {
+        self.code.iter().map(|inst| inst.instruction.clone()).collect()
+    }
+
+    fn size(&self) -> usize {
+        self.code.iter().map(|inst| inst.instruction.size()).sum()
+    }
+
+    /// The only instruction of the group, if it has one.
+    fn single(&self) -> Option<&Instruction> {
+        match self.code {
+            [inst] => Some(&inst.instruction),
+            _ => None,
+        }
+    }
+
+    /// Apply the group to `values`, forgetting anything that could move with
+    /// the layout. `JMP` always loads 0, whatever its target.
+    fn step(&self, values: &mut Values) {
+        for inst in self.code {
+            let Some((dest, value)) = cfg::write(&inst.instruction, values) else { continue };
+            let immediate = matches!(
+                inst.instruction,
+                Instruction::AddI { .. } | Instruction::LoadI { .. } | Instruction::LoadW { .. }
+            );
+            values[dest as usize] = value.filter(|_| !immediate || self.fixed || self.mnemonic == "JMP");
+        }
+    }
+}
+
+/// A rewrite of one or more consecutive groups.
+struct Candidate {
+    lines: Vec<usize>,
+    code: String,
+    reason: &'static str,
+}
+
+/// Load `value` into `dest` with the shortest instruction.
+fn load(dest: u8, value: u16) -> (Instruction, String) {
+    match u8::try_from(value) {
+        Ok(imm) => (Instruction::LoadI { dest, imm }, format!("LOADI r{} {}", dest, imm)),
+        Err(_) => (Instruction::LoadW { dest, imm: value }, format!("LOADW r{} {:#x}", dest, value)),
+    }
+}
+
+/// The register `inst` writes and the registers it reads, for instructions
+/// that can be folded into one load.
+fn chain_register(inst: &Instruction) -> Option<(u8, Vec<u8>)> {
+    match *inst {
+        Instruction::Add { dest, src } | Instruction::Nand { dest, src } => Some((dest, vec![dest, src])),
+        Instruction::AddI { dest, .. } => Some((dest, vec![dest])),
+        Instruction::LoadI { dest, .. } | Instruction::LoadW { dest, .. } => Some((dest, Vec::new())),
+        Instruction::Gt { dest, src1, src2 } => Some((dest, vec![src1, src2])),
+        _ => None,
+    }
+}
+
+/// Groups `groups[from..]` can take part in a rewrite.
+fn run<'a, 'b>(groups: &'a [Group<'b>], from: usize) -> impl Iterator<Item = &'a Group<'b>> {
+    groups[from..]
+        .iter()
+        .enumerate()
+        .take_while(|(idx, group)| (*idx == 0 || !group.entry) && group.line.is_some() && group.single().is_some())
+        .map(|(_, group)| group)
+}
+
+/// A run of instructions that only change one register, to a value known
+/// at the end, replaced by a single load of that value.
+fn fold(groups: &[Group], from: usize, values: &Values) -> Option<(usize, Candidate)> {
+    let mut best = None;
+    let mut dest = None;
+    let mut current = *values;
+    let mut size = 0;
+    for (len, group) in run(groups, from).enumerate() {
+        let Some((reg, reads)) = chain_register(group.single()?) else { break };
+        if *dest.get_or_insert(reg) != reg || reads.iter().any(|&read| read != reg && values[read as usize].is_none()) {
+            break;
+        }
+        group.step(&mut current);
+        size += group.size();
+        match current[reg as usize] {
+            Some(value) if len > 0 && load(reg, value).0.size() < size => best = Some((len + 1, reg, value)),
+            _ => {}
+        }
+    }
+    let (len, dest, value) = best?;
+    let code = load(dest, value).1;
+    let lines = groups[from..from + len].iter().filter_map(|group| group.line).collect();
+    Some((len, Candidate { lines, code, reason: "folded into one load" }))
+}
+
+/// A run of `ADD rX rY` with known `rY` and `ADDI rX k`, replaced by one
+/// `ADDI` of their sum.
+fn merge_adds(groups: &[Group], from: usize, values: &Values) -> Option<(usize, Candidate)> {
+    let mut dest = None;
+    let mut total: u16 = 0;
+    let mut len = 0;
+    for group in run(groups, from) {
+        let (reg, add) = match *group.single()? {
+            Instruction::Add { dest, src } if src != dest => match values[src as usize] {
+                Some(value) => (dest, value),
+                None => break,
+            },
+            Instruction::AddI { dest, imm } if group.fixed => (dest, imm.into()),
+            _ => break,
+        };
+        if *dest.get_or_insert(reg) != reg {
+            break;
+        }
+        total = total.wrapping_add(add);
+        len += 1;
+    }
+    let imm = u8::try_from(total).ok().filter(|_| len >= 2)?;
+    let dest = dest?;
+    let lines = groups[from..from + len].iter().filter_map(|group| group.line).collect();
+    let code = format!("ADDI r{} {}", dest, imm);
+    Some((len, Candidate { lines, code, reason: "additions merged into one ADDI" }))
+}
+
+/// A rewrite of the single group `group`.
+fn rewrite(group: &Group, values: &Values) -> Option<Candidate> {
+    let line = group.line?;
+    let mut loaded = *values;
+    group.step(&mut loaded);
+    let (code, reason) = match (group.mnemonic.as_str(), group.instructions().as_slice()) {
+        ("LOADI" | "LOADW" | "LI", [_]) if group.fixed && loaded == *values => {
+            (String::new(), "register already holds the value")
+        }
+        ("JMP", [Instruction::LoadI { dest, .. }, Instruction::Jz { reg, .. }]) if values[*dest as usize] == Some(0) => {
+            (format!("JZ r{} {}", reg, group.operand.as_ref()?), "scratch register is already 0")
+        }
+        ("LOADW" | "LI", [Instruction::LoadW { dest, imm }]) if *imm <= 0xff => {
+            (format!("LOADI r{} {}", dest, group.operand.as_ref()?), "value fits in LOADI")
+        }
+        _ => return None,
+    };
+    Some(Candidate { lines: vec![line], code, reason })
+}
+
+/// Split the code of `assembly` into groups, one per source line.
+fn groups<'a>(program: &Program, assembly: &Assembly, code: &'a [CodeInstruction]) -> Vec<Group<'a>> {
+    let mut entries: HashSet<usize> = HashSet::new();
+    let mut listed: HashMap<(&str, usize), usize> = HashMap::new();
+    for line in &assembly.listing {
+        *listed.entry((line.file.as_str(), line.line)).or_default() += 1;
+        let source = SourceLine::new(&Rc::from(line.file.as_str()), line.line, &line.source);
+        if let (Some(address), Some(_)) = (line.address, label_definition(&source.tokens())) {
+            entries.insert(address);
+        }
+    }
+    for inst in code {
+        if let Instruction::Jz { addr, .. } = inst.instruction {
+            entries.insert(addr.into());
+        }
+    }
+
+    let mut groups = Vec::new();
+    let mut start = 0;
+    let mut next = None;
+    while start < code.len() {
+        let mut end = start + 1;
+        while end < code.len()
+            && code[end].line == code[start].line
+            && code[end].address == code[end - 1].address + code[end - 1].instruction.size()
+        {
+            end += 1;
+        }
+        let first = &code[start];
+        let listed_line = &assembly.listing[first.line];
+        let source = SourceLine::new(&Rc::from(listed_line.file.as_str()), listed_line.line, &listed_line.source);
+        let parts = source.tokens();
+        let mnemonic = parts.first().map(|part| part.text.to_uppercase()).unwrap_or_default();
+        let fixed = parts.iter().skip(1).all(|part| {
+            parse_register(part, &source).is_ok() || expr::evaluate(part, &SymbolMap::new(), &source).is_ok()
+        });
+        let operand = match mnemonic.as_str() {
+            "LOADI" | "LOADW" | "LI" if parts.len() > 2 => Some(operand_span(&parts, 2, &source).text.to_string()),
+            "JMP" if parts.len() > 1 => Some(operand_span(&parts, 1, &source).text.to_string()),
+            _ => None,
+        };
+        let line = Some(listed_line.line).filter(|&number| {
+            listed_line.file == program.file
+                && listed.get(&(listed_line.file.as_str(), number)) == Some(&1)
+                && program.statements.get(number - 1).is_some_and(|statement| {
+                    let instruction = matches!(statement.kind, StatementKind::Instruction { .. });
+                    instruction && statement.source == listed_line.source
+                })
+        });
+        // After a gap, a `HLT` or a `CALL`, only a jump or a return gets here
+        let entry = entries.contains(&first.address) || next != Some(first.address);
+        groups.push(Group { code: &code[start..end], line, mnemonic, operand, fixed, entry });
+        let last = &code[end - 1];
+        next = match last.instruction {
+            Instruction::Invalid => None,
+            // The routine a `CALL` runs may change any register
+            _ if last.transfer.is_some() => None,
+            _ => Some(last.address + last.instruction.size()),
+        };
+        start = end;
+    }
+    groups
+}
+
+/// The rewrites to make to a program that assembled to `assembly`, leaving
+/// out those on `rejected` lines.
+fn candidates(program: &Program, assembly: &Assembly, rejected: &HashSet<usize>) -> Vec<Candidate> {
+    let code = cfg::decode(assembly);
+    let groups = groups(program, assembly, &code);
+    let mut found = Vec::new();
+    let mut values: Values = [None; 8];
+    let mut idx = 0;
+    while idx < groups.len() {
+        if groups[idx].entry {
+            values = [None; 8];
+        }
+        let (len, candidate) = match fold(&groups, idx, &values).or_else(|| merge_adds(&groups, idx, &values)) {
+            Some((len, candidate)) => (len, Some(candidate)),
+            None => (1, rewrite(&groups[idx], &values)),
+        };
+        found.extend(candidate.filter(|candidate| candidate.lines.iter().all(|line| !rejected.contains(line))));
+        for group in &groups[idx..idx + len] {
+            group.step(&mut values);
+        }
+        idx += len;
+    }
+    found
+}
+
+/// `statement` with its code replaced by `code`, keeping its indentation
+/// and comment.
+fn replace(statement: &Statement, code: &str) -> String {
+    let indent = &statement.source[..statement.source.len() - statement.source.trim_start().len()];
+    match &statement.comment {
+        Some(comment) if code.is_empty() => format!("{}{}", indent, comment.text),
+        Some(comment) => format!("{}{} {}", indent, code, comment.text),
+        None if code.is_empty() => String::new(),
+        None => format!("{}{}", indent, code),
+    }
+}
+
+/// The `.equ` constants of `assembly` whose values do not depend on where
+/// any label is placed.
+fn fixed_constants(assembly: &Assembly) -> SymbolMap {
+    let mut constants = SymbolMap::new();
+    // An `.equ` may use one defined after it
+    loop {
+        let known = constants.len();
+        for line in &assembly.listing {
+            let source = SourceLine::new(&Rc::from(line.file.as_str()), line.line, &line.source);
+            let parts = source.tokens();
+            if parts.len() < 3 || !parts[0].text.eq_ignore_ascii_case(".equ") || constants.contains_key(parts[1].text) {
+                continue;
+            }
+            if let Ok(value) = expr::evaluate(&operand_span(&parts, 2, &source), &constants, &source) {
+                constants.insert(parts[1].text.to_string(), value);
+            }
+        }
+        if constants.len() == known {
+            return constants;
+        }
+    }
+}
+
+/// Whether `operand` is just the name of a label, which moves with the
+/// instruction or data it marks.
+fn is_label(operand: &str, constants: &SymbolMap, assembly: &Assembly) -> bool {
+    let constant = assembly.symbols.iter()
+        .any(|symbol| symbol.kind == SymbolKind::Constant && symbol.name == operand);
+    let name = is_symbol_name(operand) || numeric_reference(operand).is_some();
+    name && !constant && !constants.contains_key(operand)
+}
+
+/// Check that the code of `assembly` only depends on where things are
+/// through bare labels, which move with it. A jump to a number or an
+/// `.org` would go to, or place code at, an address the optimizer cannot
+/// move, and an offset from a code label could end up on a different
+/// instruction once code before it is removed.
+fn check_movable(assembly: &Assembly) -> Result<(), AsmErrors> {
+    let constants = fixed_constants(assembly);
+    // Offsets within data stay the same, as data is never rewritten
+    let mut data = constants.clone();
+    data.extend(assembly.symbols.iter()
+        .filter(|symbol| symbol.kind == SymbolKind::Data)
+        .map(|symbol| (symbol.name.clone(), symbol.value)));
+    let mut errors = Vec::new();
+    for line in &assembly.listing {
+        let source = SourceLine::new(&Rc::from(line.file.as_str()), line.line, &line.source);
+        let parts = source.tokens();
+        let Some(first) = parts.first() else { continue };
+        let (operand, jump) = match first.text.to_uppercase().as_str() {
+            ".ORG" => {
+                let message = "cannot optimize code placed with `.org`, as it would not move with the code before it";
+                errors.push(source.error(first, message));
+                continue;
+            }
+            "JMP" | "CALL" => (1, true),
+            "JZ" | "JNZ" => (2, true),
+            "JGT" | "JLT" | "JGE" | "JLE" | "JEQ" | "JNE" => (3, true),
+            "LOADI" | "LOADW" | "LI" | "ADDI" | "SUBI" => (2, false),
+            _ => continue,
+        };
+        if parts.len() <= operand {
+            continue;
+        }
+        let operand = operand_span(&parts, operand, &source);
+        if is_label(operand.text, &constants, assembly) {
+            continue;
+        }
+        let message = if jump && expr::evaluate(&operand, &constants, &source).is_ok() {
+            format!(
+                "cannot optimize a jump to the fixed address `{}`, which would not move with the code; use a label",
+                operand.text,
+            )
+        } else if jump {
+            format!(
+                "cannot optimize a jump to `{}`, which is computed from a label and could land on a different \
+                 instruction once code is removed; put a label on the target and jump to it",
+                operand.text,
+            )
+        } else if expr::evaluate(&operand, &data, &source).is_err() {
+            format!(
+                "cannot optimize code that uses `{}`, which is computed from a code label and could point at a \
+                 different instruction once code is removed; put a label on what it points at",
+                operand.text,
+            )
+        } else {
+            continue;
+        };
+        errors.push(source.error(&operand, message));
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(AsmErrors(errors)),
+    }
+}
+
+fn code_size(assembly: &Assembly) -> (usize, usize) {
+    let code = cfg::decode(assembly);
+    (code.iter().map(|inst| inst.instruction.size()).sum(), code.len())
+}
+
+/// Remove and shorten instructions of `program` without changing what it
+/// does: loads of values a register already holds, `JMP`s whose scratch
+/// register is already 0, runs of instructions that compute a known value
+/// or add known amounts, and `LOADW`s of values that fit in `LOADI`.
+///
+/// Only values that do not depend on where labels end up are relied on, so
+/// every label still marks the same instruction. Programs that jump to
+/// numeric addresses or to offsets from labels, load offsets from code
+/// labels, or use `.org` are refused, since removing code would move what
+/// those addresses point at. Lines keep their numbers.
+pub fn optimize(program: &Program, options: &AssemblerOptions) -> Result<Optimization, AsmErrors> {
+    let original = encode(program, options)?;
+    check_movable(&original)?;
+    let mut assembly = original.clone();
+    let mut program = program.clone();
+    let mut rewrites = Vec::new();
+    let mut rejected = HashSet::new();
+
+    for _ in 0..MAX_ROUNDS {
+        let found = candidates(&program, &assembly, &rejected);
+        if found.is_empty() {
+            break;
+        }
+        let mut lines: Vec<String> = program.statements.iter().map(|statement| statement.source.clone()).collect();
+        let mut round = Vec::new();
+        for candidate in &found {
+            for (idx, &line) in candidate.lines.iter().enumerate() {
+                let statement = &program.statements[line - 1];
+                let code = if idx == 0 { candidate.code.as_str() } else { "" };
+                let before = render(statement).trim().to_string();
+                // A fold can leave its first line as it was
+                if before == code {
+                    continue;
+                }
+                lines[line - 1] = replace(statement, code);
+                round.push(Rewrite {
+                    line,
+                    before,
+                    after: Some(code.to_string()).filter(|code| !code.is_empty()),
+                    reason: candidate.reason,
+                });
+            }
+        }
+        let next = parse_named(&lines.join("\n"), &program.file);
+        match encode(&next, options) {
+            Ok(next_assembly) => {
+                program = next;
+                assembly = next_assembly;
+                rewrites.extend(round);
+            }
+            // A value that no longer fits, say: leave the lines it came from alone
+            Err(errors) => {
+                let failed: HashSet<usize> = errors
+                    .errors()
+                    .iter()
+                    .filter(|error| error.file == program.file)
+                    .map(|error| error.line)
+                    .collect();
+                let blamed: Vec<&Candidate> =
+                    found.iter().filter(|candidate| candidate.lines.iter().any(|line| failed.contains(line))).collect();
+                let blamed = if blamed.is_empty() { found.iter().collect() } else { blamed };
+                rejected.extend(blamed.into_iter().flat_map(|candidate| candidate.lines.iter().copied()));
+            }
+        }
+    }
+
+    let (bytes_before, count_before) = code_size(&original);
+    let (bytes_after, count_after) = code_size(&assembly);
+    Ok(Optimization {
+        program,
+        rewrites,
+        bytes_saved: bytes_before.saturating_sub(bytes_after),
+        cycles_saved: count_before.saturating_sub(count_after) * CYCLES_PER_INSTRUCTION,
+    })
+}
@@ -0,0 +1,135 @@
+use super::Assembly;
+use serde_json::json;
+use std::fmt::Write;
+use std::path::Path;
+
+/// Bytes per Intel HEX data record.
+const HEX_RECORD_LEN: usize = 16;
+
+/// Words per line of `$readmemh` and Rust output.
+const WORDS_PER_LINE: usize = 8;
+
+/// A run of consecutive words in the memory image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// Byte address of the first word
+    pub address: usize,
+    pub words: Vec<u16>,
+}
+
+/// The shapes an assembled program can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Little-endian words from address 0, as loaded by the server
+    Binary,
+    /// Intel HEX records, one run of records per segment
+    IntelHex,
+    /// `$readmemh` input for a 16-bit wide memory, addressed by word
+    ReadMemH,
+    /// A JSON object with the segments and symbols
+    Json,
+    /// A Rust `const` array of segments
+    Rust,
+}
+
+impl OutputFormat {
+    pub const NAMES: [&'static str; 5] = ["bin", "ihex", "memh", "json", "rust"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bin" => Some(OutputFormat::Binary),
+            "ihex" | "hex" => Some(OutputFormat::IntelHex),
+            "memh" | "readmemh" => Some(OutputFormat::ReadMemH),
+            "json" => Some(OutputFormat::Json),
+            "rust" | "rs" => Some(OutputFormat::Rust),
+            _ => None,
+        }
+    }
+
+    /// The format implied by an output file's extension, if it names one.
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "bin" => Some(OutputFormat::Binary),
+            "hex" | "ihex" => Some(OutputFormat::IntelHex),
+            "mem" | "memh" => Some(OutputFormat::ReadMemH),
+            "json" => Some(OutputFormat::Json),
+            "rs" => Some(OutputFormat::Rust),
+            _ => None,
+        }
+    }
+}
+
+fn hex_record(address: usize, kind: u8, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+    record.extend_from_slice(data);
+    let checksum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg();
+    record.push(checksum);
+
+    let mut line = String::from(":");
+    for byte in record {
+        write!(line, "{:02X}", byte).unwrap();
+    }
+    line.push('\n');
+    line
+}
+
+fn format_words(words: &[u16]) -> Vec<String> {
+    words.iter().map(|word| format!("0x{:04x}", word)).collect()
+}
+
+impl Assembly {
+    /// The program in the given format. Every format except `Binary` only
+    /// holds the segments, so data at high addresses costs no padding.
+    pub fn render(&self, format: OutputFormat) -> Vec<u8> {
+        match format {
+            OutputFormat::Binary => self.words.iter().flat_map(|word| word.to_le_bytes()).collect(),
+            OutputFormat::IntelHex => {
+                let mut text = String::new();
+                for segment in &self.segments {
+                    let bytes: Vec<u8> = segment.words.iter().flat_map(|word| word.to_le_bytes()).collect();
+                    for (idx, chunk) in bytes.chunks(HEX_RECORD_LEN).enumerate() {
+                        text.push_str(&hex_record(segment.address + idx * HEX_RECORD_LEN, 0x00, chunk));
+                    }
+                }
+                text.push_str(&hex_record(0, 0x01, &[]));
+                text.into_bytes()
+            }
+            OutputFormat::ReadMemH => {
+                let mut text = String::new();
+                for segment in &self.segments {
+                    writeln!(text, "@{:04x}", segment.address / 2).unwrap();
+                    for chunk in segment.words.chunks(WORDS_PER_LINE) {
+                        let words: Vec<String> = chunk.iter().map(|word| format!("{:04x}", word)).collect();
+                        writeln!(text, "{}", words.join(" ")).unwrap();
+                    }
+                }
+                text.into_bytes()
+            }
+            OutputFormat::Json => {
+                let segments: Vec<_> = self.segments.iter()
+                    .map(|segment| json!({ "address": segment.address, "words": segment.words }))
+                    .collect();
+                let symbols: serde_json::Map<_, _> = self.symbols.iter()
+                    .map(|symbol| (symbol.name.clone(), json!(symbol.value)))
+                    .collect();
+                let image = json!({ "segments": segments, "symbols": symbols });
+                let mut text = serde_json::to_string_pretty(&image).unwrap();
+                text.push('\n');
+                text.into_bytes()
+            }
+            OutputFormat::Rust => {
+                let mut text = String::from("/// Segments of the program as (byte address, words)\n");
+                text.push_str("pub const PROGRAM: &[(u16, &[u16])] = &[\n");
+                for segment in &self.segments {
+                    writeln!(text, "    (0x{:04x}, &[", segment.address).unwrap();
+                    for chunk in segment.words.chunks(WORDS_PER_LINE) {
+                        writeln!(text, "        {},", format_words(chunk).join(", ")).unwrap();
+                    }
+                    text.push_str("    ]),\n");
+                }
+                text.push_str("];\n");
+                text.into_bytes()
+            }
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use super::{encode_instruction, Instruction, LineContext};
+use crate::error::AsmError;
+
+use Instruction::{Add, AddI, Gt, Jz, Load, LoadI, LoadW, Nand, Store};
+
+/// Copy `src` into `dest`. Moving a register onto itself emits nothing.
+fn mov(dest: u8, src: u8) -> Vec<Instruction> {
+    if dest == src {
+        return Vec::new();
+    }
+    vec![LoadI { dest, imm: 0 }, Add { dest, src }]
+}
+
+/// `dest = a - b` as `a + ~b + 1`, with `dest` distinct from `a` and `b`.
+fn difference(dest: u8, a: u8, b: u8) -> Vec<Instruction> {
+    let mut insts = mov(dest, b);
+    insts.extend([Nand { dest, src: dest }, AddI { dest, imm: 1 }, Add { dest, src: a }]);
+    insts
+}
+
+impl LineContext<'_> {
+    /// The declared scratch register, which must not also be one of `operands`.
+    fn scratch_register(&self, operands: &[u8]) -> Result<u8, AsmError> {
+        let mnemonic = self.mnemonic();
+        match self.scratch {
+            None => Err(self.line.error(&self.parts[0], format!(
+                "`{}` needs a scratch register; declare one with `.scratch rN`", mnemonic
+            ))),
+            Some(reg) if operands.contains(&reg) => Err(self.line.error(&self.parts[0], format!(
+                "scratch register r{} is also an operand of `{}`", reg, mnemonic
+            ))),
+            Some(reg) => Ok(reg),
+        }
+    }
+
+    /// The stack pointer set with `.stack`, which must not also be one of
+    /// `operands` or the scratch register.
+    fn stack_pointer(&self, operands: &[u8]) -> Result<u8, AsmError> {
+        let mnemonic = self.mnemonic();
+        match self.stack {
+            None => Err(self.line.error(&self.parts[0], format!(
+                "`{}` needs a stack; set one up with `.stack rN BASE SIZE`", mnemonic
+            ))),
+            Some(reg) if operands.contains(&reg) => Err(self.line.error(&self.parts[0], format!(
+                "stack pointer r{} cannot be an operand of `{}`", reg, mnemonic
+            ))),
+            Some(reg) => self.check_stack_pointer(reg),
+        }
+    }
+
+    /// Calls and returns clobber the scratch register, so the stack pointer
+    /// cannot be it.
+    fn check_stack_pointer(&self, reg: u8) -> Result<u8, AsmError> {
+        match self.scratch {
+            Some(scratch) if scratch == reg => Err(self.line.error(&self.parts[0], format!(
+                "stack pointer r{} is also the scratch register", reg
+            ))),
+            _ => Ok(reg),
+        }
+    }
+
+    /// The address `offset` bytes past the start of this line, for jumps
+    /// within an expansion.
+    fn local_target(&self, offset: usize) -> Result<u8, AsmError> {
+        if self.sizing() {
+            return Ok(0);
+        }
+        let target = self.addr + offset;
+        u8::try_from(target).map_err(|_| self.line.error(&self.parts[0], format!(
+            "`{}` at 0x{:x} needs an internal jump to 0x{:x}, but JZ can only reach 0x00-0xff",
+            self.mnemonic(), self.addr, target
+        )))
+    }
+
+    /// Jump to `target` if `cond` is non-zero, `offset` bytes into the expansion.
+    fn jump_if_nonzero(&self, cond: u8, scratch: u8, target: u8, offset: usize) -> Result<Vec<Instruction>, AsmError> {
+        let skip = self.local_target(offset + 6)?;
+        Ok(vec![
+            Jz { reg: cond, addr: skip },
+            LoadI { dest: scratch, imm: 0 },
+            Jz { reg: scratch, addr: target },
+        ])
+    }
+}
+
+/// The stack pointer, base address and size in bytes given to `.stack`.
+pub(super) fn stack_region(ctx: &LineContext) -> Result<(u8, u16, usize), AsmError> {
+    if ctx.parts.len() < 4 {
+        return Err(ctx.line.error(&ctx.parts[0], "`.stack` expects a register, a base address and a size"));
+    }
+    let sp = ctx.check_stack_pointer(ctx.register(1)?)?;
+    let base = ctx.wide_immediate(&ctx.parts[2])?;
+    if ctx.sizing() {
+        return Ok((sp, base, 0));
+    }
+    let operand = super::operand_span(ctx.parts, 3, ctx.line);
+    let size = super::expr::evaluate(&operand, ctx.symbols, ctx.line)?;
+    if base % 2 == 1 {
+        return Err(ctx.line.error(&ctx.parts[2], format!("the stack must start at an even address, not 0x{:x}", base)));
+    }
+    if size <= 0 || size % 2 == 1 || i64::from(base) + size > 0x10000 {
+        return Err(ctx.line.error(&operand, format!(
+            "the stack needs an even size that fits in memory above 0x{:x}, not {}", base, size
+        )));
+    }
+    Ok((sp, base, size as usize))
+}
+
+/// Expand a pseudo-instruction into native instructions. The number of
+/// instructions only depends on the mnemonic, its registers and constants
+/// defined before the line, so the first pass sizes it the same as the second.
+pub(super) fn expand(ctx: &LineContext) -> Result<Vec<Instruction>, AsmError> {
+    let mnemonic = ctx.mnemonic();
+    match mnemonic.as_str() {
+        "JMP" => {
+            if ctx.parts.len() < 2 {
+                return Err(ctx.line.error(&ctx.parts[0], "`JMP` expects 1 operand: a target address"));
+            }
+            let target = ctx.jump_target(&super::operand_span(ctx.parts, 1, ctx.line))?;
+            let scratch = ctx.scratch_register(&[])?;
+            Ok(vec![LoadI { dest: scratch, imm: 0 }, Jz { reg: scratch, addr: target }])
+        }
+        "MOV" | "AND" | "OR" | "XOR" | "SUB" => {
+            ctx.expect_operands(2, "2 registers")?;
+            let dest = ctx.register(1)?;
+            let src = ctx.register(2)?;
+            match mnemonic.as_str() {
+                "MOV" => Ok(mov(dest, src)),
+                "AND" => Ok(vec![Nand { dest, src }, Nand { dest, src: dest }]),
+                // x | x = x
+                "OR" if dest == src => Ok(Vec::new()),
+                // x ^ x = x - x = 0
+                "XOR" | "SUB" if dest == src => Ok(vec![LoadI { dest, imm: 0 }]),
+                "OR" => {
+                    // ~(~dest & ~src)
+                    let scratch = ctx.scratch_register(&[dest, src])?;
+                    let mut insts = vec![Nand { dest, src: dest }];
+                    insts.extend(mov(scratch, src));
+                    insts.extend([Nand { dest: scratch, src: scratch }, Nand { dest, src: scratch }]);
+                    Ok(insts)
+                }
+                "XOR" => {
+                    // The classic four NAND gates, with the scratch register
+                    // holding ~(dest & src) and then ~(src & ~(dest & src))
+                    let scratch = ctx.scratch_register(&[dest, src])?;
+                    let mut insts = mov(scratch, dest);
+                    insts.extend([
+                        Nand { dest: scratch, src },
+                        Nand { dest, src: scratch },
+                        Nand { dest: scratch, src },
+                        Nand { dest, src: scratch },
+                    ]);
+                    Ok(insts)
+                }
+                "SUB" => {
+                    let scratch = ctx.scratch_register(&[dest, src])?;
+                    let mut insts = mov(scratch, src);
+                    insts.extend([Nand { dest: scratch, src: scratch }, AddI { dest: scratch, imm: 1 }, Add { dest, src: scratch }]);
+                    Ok(insts)
+                }
+                _ => unreachable!(),
+            }
+        }
+        "NOT" => {
+            ctx.expect_operands(1, "a register")?;
+            let dest = ctx.register(1)?;
+            Ok(vec![Nand { dest, src: dest }])
+        }
+        "NEG" => {
+            ctx.expect_operands(1, "a register")?;
+            let dest = ctx.register(1)?;
+            Ok(vec![Nand { dest, src: dest }, AddI { dest, imm: 1 }])
+        }
+        "SHL" => {
+            if ctx.parts.len() < 2 {
+                return Err(ctx.line.error(&ctx.parts[0], "`SHL` expects a register and an optional shift count"));
+            }
+            let dest = ctx.register(1)?;
+            let count = if ctx.parts.len() > 2 {
+                let operand = super::operand_span(ctx.parts, 2, ctx.line);
+                // The count decides the size, so it must be known in the first pass
+                let count = match ctx.known_value(&operand) {
+                    Some(count) => count,
+                    None if ctx.sizing() => 1,
+                    None => return Err(ctx.line.error(&operand, "shift count must be a constant defined before this line")),
+                };
+                if !(0..16).contains(&count) {
+                    return Err(ctx.line.error(&operand, format!("shift count {} is out of range (expected 0-15)", count)));
+                }
+                count as usize
+            } else {
+                1
+            };
+            Ok(vec![Add { dest, src: dest }; count])
+        }
+        "JNZ" => {
+            let operand = ctx.expect_registers_and_expression(1, "a register and a target address")?;
+            let reg = ctx.register(1)?;
+            let target = ctx.jump_target(&operand)?;
+            let scratch = ctx.scratch_register(&[reg])?;
+            ctx.jump_if_nonzero(reg, scratch, target, 0)
+        }
+        "JGT" | "JLT" | "JGE" | "JLE" => {
+            let operand = ctx.expect_registers_and_expression(2, "2 registers and a target address")?;
+            let a = ctx.register(1)?;
+            let b = ctx.register(2)?;
+            let target = ctx.jump_target(&operand)?;
+            let scratch = ctx.scratch_register(&[a, b])?;
+
+            // GT is the only comparison, so swap operands for < and >=
+            let (src1, src2) = match mnemonic.as_str() {
+                "JGT" | "JLE" => (a, b),
+                _ => (b, a),
+            };
+            let mut insts = vec![Gt { dest: scratch, src1, src2 }];
+            match mnemonic.as_str() {
+                "JLE" | "JGE" => insts.push(Jz { reg: scratch, addr: target }),
+                _ => insts.extend(ctx.jump_if_nonzero(scratch, scratch, target, 2)?),
+            }
+            Ok(insts)
+        }
+        "JEQ" | "JNE" => {
+            let operand = ctx.expect_registers_and_expression(2, "2 registers and a target address")?;
+            let a = ctx.register(1)?;
+            let b = ctx.register(2)?;
+            let target = ctx.jump_target(&operand)?;
+            let scratch = ctx.scratch_register(&[a, b])?;
+
+            let mut insts = difference(scratch, a, b);
+            let offset = insts.iter().map(Instruction::size).sum();
+            if mnemonic == "JEQ" {
+                insts.push(Jz { reg: scratch, addr: target });
+            } else {
+                insts.extend(ctx.jump_if_nonzero(scratch, scratch, target, offset)?);
+            }
+            Ok(insts)
+        }
+        "SUBI" => {
+            // ~(~x + k) = x - k, which needs no scratch register
+            let operand = ctx.expect_registers_and_expression(1, "a register and a value")?;
+            let dest = ctx.register(1)?;
+            let imm = ctx.immediate(&operand)?;
+            Ok(vec![Nand { dest, src: dest }, AddI { dest, imm }, Nand { dest, src: dest }])
+        }
+        "LI" => {
+            let operand = ctx.expect_registers_and_expression(1, "a register and a value")?;
+            let dest = ctx.register(1)?;
+
+            // The short form is only used if the first pass could prove the
+            // value fits, otherwise the second pass would change the layout
+            let short = match ctx.reserved {
+                Some(reserved) => reserved == 2,
+                None => matches!(ctx.known_value(&operand), Some(value) if (0..=0xFF).contains(&value)),
+            };
+            if short {
+                Ok(vec![LoadI { dest, imm: ctx.immediate(&operand)? }])
+            } else {
+                Ok(vec![LoadW { dest, imm: ctx.wide_immediate(&operand)? }])
+            }
+        }
+        "LOADSTR" => {
+            let operand = ctx.expect_registers_and_expression(1, "a first register and a string")?;
+            let first = ctx.register(1)?;
+            let bytes = super::data::string(&operand, ctx.line)?;
+            if bytes.is_empty() {
+                return Err(ctx.line.error(&operand, "`LOADSTR` needs a non-empty string"));
+            }
+            let count = bytes.len().div_ceil(2);
+            if first as usize + count > 8 {
+                return Err(ctx.line.error(&operand, format!(
+                    "string needs {} registers starting at r{}, but the last register is r7", count, first
+                )));
+            }
+
+            // Two characters per register with the first in the high byte,
+            // as `FLAG` compares them; an odd length is padded with NUL like "o\0"
+            Ok(bytes.chunks(2)
+                .zip(first..)
+                .map(|(pair, dest)| LoadW { dest, imm: u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]) })
+                .collect())
+        }
+        // The stack grows up from its base, with the stack pointer at the
+        // first free word
+        ".STACK" => {
+            let (sp, base, _) = stack_region(ctx)?;
+            Ok(vec![LoadW { dest: sp, imm: base }])
+        }
+        "PUSH" => {
+            ctx.expect_operands(1, "a register")?;
+            let src = ctx.register(1)?;
+            let sp = ctx.stack_pointer(&[src])?;
+            Ok(vec![Store { addr: sp, src }, AddI { dest: sp, imm: 2 }])
+        }
+        "POP" => {
+            ctx.expect_operands(1, "a register")?;
+            let dest = ctx.register(1)?;
+            let sp = ctx.stack_pointer(&[dest])?;
+            Ok(vec![Nand { dest: sp, src: sp }, AddI { dest: sp, imm: 2 }, Nand { dest: sp, src: sp }, Load { dest, src: sp }])
+        }
+        "CALL" => {
+            if ctx.parts.len() < 2 {
+                return Err(ctx.line.error(&ctx.parts[0], "`CALL` expects 1 operand: a target address"));
+            }
+            let target = ctx.jump_target(&super::operand_span(ctx.parts, 1, ctx.line))?;
+            let sp = ctx.stack_pointer(&[])?;
+            let scratch = ctx.scratch_register(&[])?;
+            // The return address is pushed in the high byte, where `RET` turns it into a `JZ`
+            let ret = ctx.local_target(12)?;
+            Ok(vec![
+                LoadW { dest: scratch, imm: u16::from(ret) << 8 },
+                Store { addr: sp, src: scratch },
+                AddI { dest: sp, imm: 2 },
+                LoadI { dest: scratch, imm: 0 },
+                Jz { reg: scratch, addr: target },
+            ])
+        }
+        "RET" => {
+            ctx.expect_operands(0, "RET")?;
+            let sp = ctx.stack_pointer(&[])?;
+            let scratch = ctx.scratch_register(&[])?;
+            // There is no indirect jump, so the return address is stored
+            // into the final `JZ` of this expansion. The stack pointer is
+            // needed to address it, so it is saved into the `LOADW` before
+            // that `JZ` first. Both are used right after they are written,
+            // which keeps routines reentrant
+            let saved_sp = (ctx.addr + 24) as u16;
+            let jump = (ctx.addr + 28) as u16;
+            let jz = encode_instruction(Jz { reg: scratch, addr: 0 }) as u8;
+            Ok(vec![
+                Nand { dest: sp, src: sp },
+                AddI { dest: sp, imm: 2 },
+                Nand { dest: sp, src: sp },
+                LoadW { dest: scratch, imm: saved_sp },
+                Store { addr: scratch, src: sp },
+                Load { dest: scratch, src: sp },
+                AddI { dest: scratch, imm: jz },
+                LoadW { dest: sp, imm: jump },
+                Store { addr: sp, src: scratch },
+                LoadW { dest: sp, imm: 0 },
+                LoadI { dest: scratch, imm: 0 },
+                Jz { reg: scratch, addr: 0 },
+            ])
+        }
+        _ => Err(ctx.line.error(&ctx.parts[0], format!("unknown instruction `{}`", ctx.parts[0].text))),
+    }
+}
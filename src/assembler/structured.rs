@@ -0,0 +1,267 @@
+use super::{expr, label_definition, operand_span, SourceLine, SymbolMap, Token};
+use crate::error::AsmError;
+
+/// The kinds of label the directives generate, each followed by `_` and
+/// the number of the block.
+const GENERATED: [&str; 6] = ["__while", "__endw", "__else", "__endif", "__for", "__endf"];
+
+/// Whether `label` is one the structured directives generated. These do
+/// not start a new scope for local labels, as labels written in the
+/// source do.
+pub(super) fn is_generated(label: &str) -> bool {
+    GENERATED.iter().any(|prefix| {
+        label.strip_prefix(prefix)
+            .and_then(|rest| rest.strip_prefix('_'))
+            .is_some_and(|number| !number.is_empty() && number.bytes().all(|c| c.is_ascii_digit()))
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    While,
+    Ifz,
+    For,
+}
+
+impl Kind {
+    fn opener(self) -> &'static str {
+        match self {
+            Kind::While => ".while",
+            Kind::Ifz => ".ifz",
+            Kind::For => ".for",
+        }
+    }
+}
+
+/// A block whose closing directive has not been seen yet.
+struct Open {
+    kind: Kind,
+    id: usize,
+    opener: SourceLine,
+    /// The counter of a `.for`
+    counter: String,
+    /// Whether an `.ifz` is past its `.else`
+    in_else: bool,
+}
+
+struct Lowering {
+    open: Vec<Open>,
+    blocks: usize,
+    /// The register named by the last `.scratch`, as written
+    scratch: Option<String>,
+    /// `.equ` constants with a known value so far, for `.while` conditions
+    constants: SymbolMap,
+    output: Vec<SourceLine>,
+    errors: Vec<AsmError>,
+}
+
+/// The jump to `target` taken when `.while`'s condition `a op b` is false.
+/// Comparisons with a constant 0, which `zero` says `b` is, only test the
+/// register; other comparisons are between two registers and use the
+/// pseudo-instructions built on `GT`. Registers are unsigned, so `> 0` is
+/// `!= 0` and `<= 0` is `== 0`, while `< 0` and `>= 0` never change.
+fn exit_jump(a: &str, op: &str, b: &str, zero: bool, target: &str) -> Result<String, String> {
+    let jump = match (op, zero) {
+        ("!=" | ">", true) => return Ok(format!("JZ {} {}", a, target)),
+        ("==" | "<=", true) => return Ok(format!("JNZ {} {}", a, target)),
+        ("<", true) => return Err(format!("`{} < {}` is never true, as registers are unsigned", a, b)),
+        (">=", true) => return Err(format!("`{} >= {}` is always true, as registers are unsigned", a, b)),
+        (">", _) => "JLE",
+        ("<", _) => "JGE",
+        (">=", _) => "JLT",
+        ("<=", _) => "JGT",
+        ("==", _) => "JNE",
+        ("!=", _) => "JEQ",
+        _ => return Err(format!("unknown comparison `{}` in `.while`; use `==`, `!=`, `>`, `<`, `>=` or `<=`", op)),
+    };
+    Ok(format!("{} {} {} {}", jump, a, b, target))
+}
+
+impl Lowering {
+    /// Emit a line generated for `directive`. Instructions that use the
+    /// scratch register say which one in a comment, so the listing shows it.
+    fn emit(&mut self, directive: &SourceLine, text: String, scratch: bool) {
+        let text = match (&self.scratch, text.ends_with(':')) {
+            (_, true) => text,
+            (Some(reg), false) if scratch => format!("        {} ; scratch {}", text, reg),
+            _ => format!("        {}", text),
+        };
+        let token = directive.tokens()[0];
+        let mut notes = vec![AsmError::new(
+            &directive.file, directive.number, token.column + 1, token.text.len(), &directive.text,
+            format!("generated for this `{}`", token.text),
+        )];
+        notes.extend(directive.notes.iter().cloned());
+        self.output.push(SourceLine { file: directive.file.clone(), number: directive.number, text, notes, aliases: Vec::new() });
+    }
+
+    fn open(&mut self, kind: Kind, line: &SourceLine, counter: String) -> usize {
+        self.blocks += 1;
+        self.open.push(Open { kind, id: self.blocks, opener: line.clone(), counter, in_else: false });
+        self.blocks
+    }
+
+    /// The innermost block, if it is of kind `kind`.
+    fn close(&mut self, kind: Kind, line: &SourceLine, token: &Token) -> Option<Open> {
+        if self.open.last().is_some_and(|open| open.kind == kind) {
+            return self.open.pop();
+        }
+        let message = match self.open.last() {
+            Some(open) => format!(
+                "`{}` inside the `{}` on line {}, which must be closed first",
+                token.text, open.kind.opener(), open.opener.number,
+            ),
+            None => format!("`{}` without a matching `{}`", token.text, kind.opener()),
+        };
+        self.errors.push(line.error(token, message));
+        None
+    }
+
+    fn directive(&mut self, line: SourceLine) {
+        let parts = line.tokens();
+        match parts[0].text {
+            ".while" => {
+                let target = format!("__endw_{}", self.blocks + 1);
+                if parts.len() < 4 {
+                    let message = "`.while` expects a condition such as `r0 != 0` or `r0 > r1`";
+                    self.errors.push(line.error(&parts[0], message));
+                    return;
+                }
+                // Anything that is not a constant is taken to be a register
+                let b = operand_span(&parts, 3, &line);
+                let jump = match expr::evaluate(&b, &self.constants, &line) {
+                    Ok(0) => exit_jump(parts[1].text, parts[2].text, b.text, true, &target),
+                    Ok(_) => {
+                        let message = "`.while` can only compare a register with 0 or with another register";
+                        self.errors.push(line.error(&b, message));
+                        return;
+                    }
+                    Err(_) => exit_jump(parts[1].text, parts[2].text, b.text, false, &target),
+                };
+                let jump = match jump {
+                    Ok(jump) => jump,
+                    Err(message) => {
+                        self.errors.push(line.error(&parts[2], message));
+                        return;
+                    }
+                };
+                let id = self.open(Kind::While, &line, String::new());
+                let scratch = !jump.starts_with("JZ ");
+                self.emit(&line, format!("__while_{}:", id), false);
+                self.emit(&line, jump, scratch);
+            }
+            ".endw" => {
+                if let Some(open) = self.close(Kind::While, &line, &parts[0]) {
+                    self.emit(&line, format!("JMP __while_{}", open.id), true);
+                    self.emit(&line, format!("__endw_{}:", open.id), false);
+                }
+            }
+            ".ifz" => {
+                if parts.len() != 2 {
+                    self.errors.push(line.error(&parts[0], "`.ifz` expects a register"));
+                    return;
+                }
+                let id = self.open(Kind::Ifz, &line, String::new());
+                self.emit(&line, format!("JNZ {} __else_{}", parts[1].text, id), true);
+            }
+            ".else" => {
+                if let Some(mut open) = self.close(Kind::Ifz, &line, &parts[0]) {
+                    // Already reported when conditionals were resolved
+                    if !open.in_else {
+                        self.emit(&line, format!("JMP __endif_{}", open.id), true);
+                        self.emit(&line, format!("__else_{}:", open.id), false);
+                    }
+                    open.in_else = true;
+                    self.open.push(open);
+                }
+            }
+            ".endif" => {
+                if let Some(open) = self.close(Kind::Ifz, &line, &parts[0]) {
+                    if !open.in_else {
+                        self.emit(&line, format!("__else_{}:", open.id), false);
+                    }
+                    self.emit(&line, format!("__endif_{}:", open.id), false);
+                }
+            }
+            ".for" => {
+                if parts.len() < 3 {
+                    self.errors.push(line.error(&parts[0], "`.for` expects a register and a count"));
+                    return;
+                }
+                let counter = parts[1].text.to_string();
+                let count = operand_span(&parts, 2, &line).text.to_string();
+                let id = self.open(Kind::For, &line, counter.clone());
+                self.emit(&line, format!("LI {} {}", counter, count), false);
+                self.emit(&line, format!("__for_{}:", id), false);
+                self.emit(&line, format!("JZ {} __endf_{}", counter, id), false);
+            }
+            ".endf" => {
+                if let Some(open) = self.close(Kind::For, &line, &parts[0]) {
+                    self.emit(&line, format!("SUBI {} 1", open.counter), false);
+                    self.emit(&line, format!("JMP __for_{}", open.id), true);
+                    self.emit(&line, format!("__endf_{}:", open.id), false);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Lower the structured control-flow directives to jumps between
+/// generated labels, after macros and conditionals are resolved:
+///
+/// - `.while rX != 0` ... `.endw` repeats its body while the condition
+///   holds, testing it before each pass. The condition may also compare
+///   with `==` against 0, or compare two registers with `==`, `!=`, `>`,
+///   `<`, `>=` or `<=`.
+/// - `.ifz rX` ... `.else` ... `.endif` runs the first part if `rX` is 0
+///   and the `.else` part, which is optional, otherwise.
+/// - `.for rX count` ... `.endf` runs its body `count` times, with `rX`
+///   counting down from `count` to 1.
+///
+/// Jumps that are not a single `JZ` clobber the scratch register set with
+/// `.scratch`, which the generated lines name in a comment. Labels in the
+/// source may not use the names generated for the blocks.
+pub(super) fn lower(lines: Vec<SourceLine>, errors: &mut Vec<AsmError>) -> Vec<SourceLine> {
+    let mut lowering = Lowering {
+        open: Vec::new(),
+        blocks: 0,
+        scratch: None,
+        constants: SymbolMap::new(),
+        output: Vec::new(),
+        errors: Vec::new(),
+    };
+    for line in lines {
+        let parts = line.tokens();
+        if label_definition(&parts).is_some_and(is_generated) {
+            let message = format!("`{}` is reserved for the labels of `.while`, `.ifz` and `.for`", parts[0].text.trim_end_matches(':'));
+            lowering.errors.push(line.error(&parts[0], message));
+            continue;
+        }
+        match parts.first().map(|token| token.text) {
+            Some(".while" | ".endw" | ".ifz" | ".else" | ".endif" | ".for" | ".endf") => lowering.directive(line),
+            Some(".scratch") => {
+                lowering.scratch = parts.get(1).map(|token| token.text.to_string());
+                lowering.output.push(line);
+            }
+            Some(".equ") if parts.len() >= 3 => {
+                if let Ok(value) = expr::evaluate(&operand_span(&parts, 2, &line), &lowering.constants, &line) {
+                    lowering.constants.insert(parts[1].text.to_string(), value);
+                }
+                lowering.output.push(line);
+            }
+            _ => lowering.output.push(line),
+        }
+    }
+
+    for open in lowering.open {
+        // An open `.ifz` was reported with the other conditionals
+        if open.kind != Kind::Ifz {
+            let token = open.opener.tokens()[0];
+            let close = if open.kind == Kind::While { ".endw" } else { ".endf" };
+            lowering.errors.push(open.opener.error(&token, format!("unterminated `{}`, expected `{}`", token.text, close)));
+        }
+    }
+    errors.extend(lowering.errors);
+    lowering.output
+}
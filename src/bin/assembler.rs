@@ -1,31 +1,217 @@
 use std::env;
 use std::fs;
 use std::error::Error;
-use std::io::Write;
-use verilog_ctf::assembler::assemble;
+use std::path::{Path, PathBuf};
+use verilog_ctf::assembler::{
+    assemble_file_with, assemble_object_file, encode, format, lint, optimize, parse_named, AssemblerOptions, Assembly, Cfg, Layout,
+    Lint, LintConfig, OutputFormat, Severity,
+};
+use verilog_ctf::error::AsmErrors;
+
+fn usage(program: &str) -> ! {
+    eprintln!(
+        "Usage: {} [-I <include_dir>]... [-D <name>[=<value>]]... [--layout <file>] [--format <{}>] [--listing <file>] [--symbols <file>] [--cfg <file>] [--optimize] <input_file> <output_file>",
+        program,
+        OutputFormat::NAMES.join("|")
+    );
+    eprintln!("       {} -c [-I <include_dir>]... [-D <name>[=<value>]]... <input_file> <object_file>", program);
+    eprintln!("       {} --fmt [--check] <input_file>...", program);
+    eprintln!("       {} --lint [-I <include_dir>]... [-D <name>[=<value>]]... [--allow|--warn|--deny <lint>]... <input_file>", program);
+    eprintln!("The format defaults to the one implied by the output file's extension, or bin.");
+    eprintln!("--cfg writes the control-flow graph as JSON if the file name ends in .json, or as Graphviz DOT.");
+    eprintln!("With --optimize, remove and shorten instructions first, reporting each change and the bytes and cycles saved.");
+    eprintln!("Programs that jump to numeric addresses or label offsets, or use .org, are not optimized, as their code cannot move.");
+    eprintln!("With -c, write an object for the linker instead of a memory image.");
+    eprintln!("With --lint, report likely mistakes; the lints are {}.", Lint::ALL.map(Lint::name).join(", "));
+    eprintln!("With --fmt, rewrite the files in the standard layout; with --check, only list the ones that differ from it.");
+    std::process::exit(1);
+}
+
+fn abort(errors: AsmErrors) -> ! {
+    eprintln!("{}", errors);
+    eprintln!();
+    eprintln!("{}", errors.summary());
+    std::process::exit(1);
+}
+
+fn define(options: &mut AssemblerOptions, spec: &str, program: &str) {
+    if let Err(message) = options.define(spec) {
+        eprintln!("error: {}", message);
+        usage(program);
+    }
+}
+
+/// Format the files in place, or with `check`, list those that are not formatted.
+fn format_files(paths: &[&String], check: bool) -> Result<(), Box<dyn Error>> {
+    let mut unformatted = 0;
+    for path in paths {
+        let source = fs::read_to_string(path)?;
+        let formatted = format(&source);
+        if formatted == source {
+            continue;
+        }
+        if check {
+            eprintln!("{} is not formatted", path);
+            unformatted += 1;
+        } else {
+            fs::write(path, formatted)?;
+        }
+    }
+    if unformatted > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Print the lints for a file, failing if any of them is denied.
+fn lint_file(path: &str, options: &AssemblerOptions, config: &LintConfig) -> Result<(), Box<dyn Error>> {
+    let source = fs::read_to_string(path)?;
+    let diagnostics = lint(&parse_named(&source, path), options, config).unwrap_or_else(|errors| abort(errors));
+    for diagnostic in &diagnostics {
+        eprintln!("{}", diagnostic);
+        eprintln!();
+    }
+    if diagnostics.iter().any(|diagnostic| diagnostic.severity == Severity::Deny) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Assemble a file after optimizing it, describing what changed.
+fn optimize_file(path: &str, options: &AssemblerOptions) -> Result<Assembly, Box<dyn Error>> {
+    let source = fs::read_to_string(path).map_err(|e| format!("cannot read `{}`: {}", path, e))?;
+    let optimization = optimize(&parse_named(&source, path), options).unwrap_or_else(|errors| abort(errors));
+    for rewrite in &optimization.rewrites {
+        match &rewrite.after {
+            Some(after) => eprintln!("{}:{}: `{}` -> `{}`: {}", path, rewrite.line, rewrite.before, after, rewrite.reason),
+            None => eprintln!("{}:{}: removed `{}`: {}", path, rewrite.line, rewrite.before, rewrite.reason),
+        }
+    }
+    eprintln!("saved {} bytes and {} cycles", optimization.bytes_saved, optimization.cycles_saved);
+    Ok(encode(&optimization.program, options).unwrap_or_else(|errors| abort(errors)))
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = env::args().collect();
-    
-    if args.len() != 3 {
-        eprintln!("Usage: {} <input_file> <output_file>", args[0]);
-        std::process::exit(1);
+
+    let mut options = AssemblerOptions::default();
+    let mut object = false;
+    let mut fmt = false;
+    let mut check = false;
+    let mut lint_mode = false;
+    let mut optimizing = false;
+    let mut lints = LintConfig::default();
+    let mut format = None;
+    let mut listing_path = None;
+    let mut symbols_path = None;
+    let mut cfg_path = None;
+    let mut paths = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        if ["-I", "-D", "--layout", "--format", "--listing", "--symbols", "--cfg", "--allow", "--warn", "--deny"].contains(&arg.as_str()) {
+            let value = match rest.next() {
+                Some(value) => value,
+                None => usage(&args[0]),
+            };
+            match arg.as_str() {
+                "-I" => options.include_paths.push(PathBuf::from(value)),
+                "-D" => define(&mut options, value, &args[0]),
+                "--layout" => options.layout = Layout::from_file(value).unwrap_or_else(|errors| abort(errors)),
+                "--format" => match OutputFormat::from_name(value) {
+                    Some(name) => format = Some(name),
+                    None => {
+                        eprintln!("error: unknown output format `{}`", value);
+                        usage(&args[0]);
+                    }
+                },
+                "--allow" | "--warn" | "--deny" => match Lint::from_name(value) {
+                    Some(name) => {
+                        let severity = Severity::from_name(&arg[2..]).unwrap();
+                        lints.set(name, severity);
+                    }
+                    None => {
+                        eprintln!("error: unknown lint `{}`", value);
+                        usage(&args[0]);
+                    }
+                },
+                "--listing" => listing_path = Some(value),
+                "--cfg" => cfg_path = Some(value),
+                _ => symbols_path = Some(value),
+            }
+        } else if arg == "-c" {
+            object = true;
+        } else if arg == "--fmt" {
+            fmt = true;
+        } else if arg == "--check" {
+            check = true;
+        } else if arg == "--lint" {
+            lint_mode = true;
+        } else if arg == "--optimize" {
+            optimizing = true;
+        } else if let Some(dir) = arg.strip_prefix("-I") {
+            options.include_paths.push(PathBuf::from(dir));
+        } else if let Some(spec) = arg.strip_prefix("-D") {
+            define(&mut options, spec, &args[0]);
+        } else {
+            paths.push(arg);
+        }
     }
 
-    let input_path = &args[1];
-    let output_path = &args[2];
+    if fmt || check {
+        if !fmt || paths.is_empty() {
+            usage(&args[0]);
+        }
+        return format_files(&paths, check);
+    }
 
-    // Read input file
-    let input = fs::read_to_string(input_path)?;
+    if lint_mode {
+        if paths.len() != 1 {
+            usage(&args[0]);
+        }
+        return lint_file(paths[0], &options, &lints);
+    }
+
+    if paths.len() != 2 {
+        usage(&args[0]);
+    }
+    let input_path = paths[0];
+    let output_path = paths[1];
+
+    // Objects are placed by the linker, which writes the image and listings
+    if object {
+        if format.is_some() || listing_path.is_some() || symbols_path.is_some() || cfg_path.is_some() || optimizing {
+            eprintln!("error: -c cannot be combined with --format, --listing, --symbols, --cfg or --optimize");
+            usage(&args[0]);
+        }
+        let object = assemble_object_file(input_path, &options).unwrap_or_else(|errors| abort(errors));
+        fs::write(output_path, object.to_json())?;
+        return Ok(());
+    }
 
     // Assemble the program
-    let assembled = assemble(&input)?;
+    let assembled = match optimizing {
+        true => optimize_file(input_path, &options)?,
+        false => assemble_file_with(input_path, &options).unwrap_or_else(|errors| abort(errors)),
+    };
 
-    // Write output as binary
-    let mut output_file = fs::File::create(output_path)?;
-    for word in assembled {
-        output_file.write_all(&word.to_le_bytes())?;
+    let format = format
+        .or_else(|| OutputFormat::from_extension(Path::new(output_path)))
+        .unwrap_or(OutputFormat::Binary);
+    fs::write(output_path, assembled.render(format))?;
+
+    if let Some(path) = listing_path {
+        fs::write(path, assembled.listing_text())?;
+    }
+    if let Some(path) = symbols_path {
+        fs::write(path, assembled.symbol_map_text())?;
+    }
+    if let Some(path) = cfg_path {
+        let cfg = Cfg::new(&assembled);
+        match Path::new(path).extension().is_some_and(|extension| extension == "json") {
+            true => fs::write(path, cfg.to_json())?,
+            false => fs::write(path, cfg.to_dot())?,
+        }
     }
 
     Ok(())
-} 
\ No newline at end of file
+}
@@ -0,0 +1,70 @@
+use std::collections::HashSet;
+use std::env;
+use std::error::Error;
+use std::fs;
+use verilog_ctf::assembler::Instruction;
+use verilog_ctf::disassembler::{disassemble, is_undecodable, Decoded};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() != 2 && args.len() != 3 {
+        eprintln!("Usage: {} <input_file> [output_file]", args[0]);
+        std::process::exit(1);
+    }
+
+    // Read input file as little-endian words
+    let bytes = fs::read(&args[1])?;
+    let words: Vec<u16> = bytes
+        .chunks(2)
+        .map(|chunk| u16::from_le_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]))
+        .collect();
+
+    let program = disassemble(&words);
+
+    // Only jump targets that start an instruction can get a label, anything
+    // else (e.g. the middle of a LOADW) is left as a numeric address.
+    let starts: HashSet<usize> = program.iter().map(|(addr, _)| *addr).collect();
+    let targets: HashSet<usize> = program
+        .iter()
+        .filter_map(|(_, inst)| match inst {
+            Decoded::Instruction(Instruction::Jz { addr, .. }) if starts.contains(&(*addr as usize)) => Some(*addr as usize),
+            _ => None,
+        })
+        .collect();
+
+    let mut output = String::new();
+    for (addr, inst) in &program {
+        if targets.contains(addr) {
+            output.push_str(&format!("L_{:04x}:\n", addr));
+        }
+
+        let text = match inst {
+            Decoded::Instruction(Instruction::Jz { reg, addr: target }) if targets.contains(&(*target as usize)) => {
+                format!("JZ r{} L_{:04x}", reg, target)
+            }
+            _ => inst.to_string(),
+        };
+
+        let word = words[addr / 2];
+        let raw = match inst {
+            Decoded::Instruction(Instruction::LoadW { imm, .. }) => format!("{:04x} {:04x}", word, imm),
+            _ => format!("{:04x}", word),
+        };
+
+        let mut line = format!("    {:<24}; {:04x}: {}", text, addr, raw);
+        if is_undecodable(word) {
+            line.push_str(&format!(" (invalid opcode 0x{:x})", word & 0xF));
+        }
+        output.push_str(line.trim_end());
+        output.push('\n');
+    }
+
+    if args.len() == 3 {
+        fs::write(&args[2], output)?;
+    } else {
+        print!("{}", output);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use std::env;
+use std::fs;
+use std::error::Error;
+use std::path::Path;
+use verilog_ctf::assembler::{link, Layout, Object, OutputFormat};
+use verilog_ctf::error::AsmErrors;
+
+fn usage(program: &str) -> ! {
+    eprintln!(
+        "Usage: {} [--layout <file>] [--format <{}>] [--listing <file>] [--symbols <file>] -o <output_file> <object_file>...",
+        program,
+        OutputFormat::NAMES.join("|")
+    );
+    eprintln!("Objects are written by `assembler -c`. Without a layout, text is placed at 0 and other sections follow it.");
+    std::process::exit(1);
+}
+
+fn abort(errors: AsmErrors) -> ! {
+    eprintln!("{}", errors);
+    eprintln!();
+    eprintln!("{}", errors.summary());
+    std::process::exit(1);
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().collect();
+
+    let mut layout = Layout::default();
+    let mut output_path = None;
+    let mut format = None;
+    let mut listing_path = None;
+    let mut symbols_path = None;
+    let mut inputs = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        if ["-o", "--layout", "--format", "--listing", "--symbols"].contains(&arg.as_str()) {
+            let value = match rest.next() {
+                Some(value) => value,
+                None => usage(&args[0]),
+            };
+            match arg.as_str() {
+                "-o" => output_path = Some(value),
+                "--layout" => layout = Layout::from_file(value).unwrap_or_else(|errors| abort(errors)),
+                "--format" => match OutputFormat::from_name(value) {
+                    Some(name) => format = Some(name),
+                    None => {
+                        eprintln!("error: unknown output format `{}`", value);
+                        usage(&args[0]);
+                    }
+                },
+                "--listing" => listing_path = Some(value),
+                _ => symbols_path = Some(value),
+            }
+        } else {
+            inputs.push(arg);
+        }
+    }
+
+    let output_path = match output_path {
+        Some(path) if !inputs.is_empty() => path,
+        _ => usage(&args[0]),
+    };
+
+    let mut objects = Vec::new();
+    for input in inputs {
+        let text = fs::read_to_string(input)?;
+        let object = Object::from_json(&text).map_err(|e| format!("{} is not an object file: {}", input, e))?;
+        objects.push(object);
+    }
+
+    let linked = link(&objects, &layout).unwrap_or_else(|errors| abort(errors));
+
+    let format = format
+        .or_else(|| OutputFormat::from_extension(Path::new(output_path)))
+        .unwrap_or(OutputFormat::Binary);
+    fs::write(output_path, linked.render(format))?;
+
+    if let Some(path) = listing_path {
+        fs::write(path, linked.listing_text())?;
+    }
+    if let Some(path) = symbols_path {
+        fs::write(path, linked.symbol_map_text())?;
+    }
+
+    Ok(())
+}
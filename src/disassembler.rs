@@ -0,0 +1,82 @@
+use crate::assembler::{encode_instruction, Instruction};
+use std::fmt;
+
+/// One entry of a disassembled image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decoded {
+    Instruction(Instruction),
+    /// A word that is not disassembled, because assembling its instruction
+    /// would not give it back: unused bits that are set, an opcode that only
+    /// halts, or a LOADW with no immediate after it. It is written back as
+    /// `.word` so the image is unchanged.
+    Word(u16),
+}
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decoded::Instruction(inst) => write!(f, "{}", inst),
+            Decoded::Word(word) => write!(f, ".word 0x{:04x}", word),
+        }
+    }
+}
+
+/// Decode a single instruction word. `next` is the word that follows it in
+/// memory, which only matters for the two-word LOADW form. Returns the
+/// instruction and the number of words it occupies.
+pub fn decode_instruction(word: u16, next: Option<u16>) -> (Instruction, usize) {
+    let opcode = word & 0xF;
+    let dest = ((word >> 4) & 0x7) as u8;      // reg_dest [6:4]
+    let src = ((word >> 8) & 0x7) as u8;       // reg_src [10:8]
+    let src2 = ((word >> 12) & 0x7) as u8;     // reg_src2 [14:12]
+    let imm = (word >> 8) as u8;               // immediate [15:8]
+
+    let inst = match opcode {
+        0x0 => Instruction::Nop,
+        0x1 => Instruction::Add { dest, src },
+        0x4 => Instruction::AddI { dest, imm },
+        0x6 => Instruction::Nand { dest, src },
+        0x7 => Instruction::Gt { dest, src1: src, src2 },
+        0x8 => Instruction::LoadI { dest, imm },
+        0x9 => Instruction::Store { addr: dest, src },
+        0xB => Instruction::Load { dest, src },
+        0xC => Instruction::Jz { reg: dest, addr: imm },
+        0xD => {
+            // The CPU reads the immediate from the following word, which is
+            // zero if we are at the end of the image.
+            return (Instruction::LoadW { dest, imm: next.unwrap_or(0) }, 2);
+        }
+        0xE => Instruction::Flag,
+        // 0x2, 0x3, 0x5, 0xA and 0xF all fall through to the `default`
+        // case in cpu.v, which halts the processor.
+        _ => Instruction::Invalid,
+    };
+    (inst, 1)
+}
+
+/// Returns true if the opcode of `word` is not a real instruction and only
+/// decodes as a halt. 0xF is the canonical HLT encoding and is not reported.
+pub fn is_undecodable(word: u16) -> bool {
+    matches!(word & 0xF, 0x2 | 0x3 | 0x5 | 0xA)
+}
+
+/// Disassemble a memory image into `(byte address, instruction)` pairs.
+/// Words that assembling their instruction would change are kept as raw
+/// words, so the output assembles to the same image.
+pub fn disassemble(words: &[u16]) -> Vec<(usize, Decoded)> {
+    let mut result = Vec::new();
+    let mut idx = 0;
+
+    while idx < words.len() {
+        let (inst, len) = decode_instruction(words[idx], words.get(idx + 1).copied());
+        if idx + len > words.len() || encode_instruction(inst.clone()) != words[idx] {
+            result.push((idx * 2, Decoded::Word(words[idx])));
+            idx += 1;
+            continue;
+        }
+        result.push((idx * 2, Decoded::Instruction(inst)));
+        idx += len;
+    }
+
+    result
+}
@@ -1,2 +1,109 @@
-// This file is intentionally empty.
-// The err macro is defined in lib.rs 
\ No newline at end of file
+// The err macro is defined in lib.rs
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+/// A diagnostic produced by the assembler, pointing at a span of a source line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AsmError {
+    pub file: String,
+    /// 1-based line number, or 0 if the error concerns the whole file
+    pub line: usize,
+    /// 1-based column of the start of the span
+    pub column: usize,
+    /// Width of the span in characters
+    pub len: usize,
+    /// The full source line the span refers to
+    pub snippet: String,
+    pub message: String,
+    /// Related locations, such as the macro call a line was expanded from
+    pub notes: Vec<AsmError>,
+}
+
+impl AsmError {
+    pub fn new(file: &str, line: usize, column: usize, len: usize, snippet: &str, message: impl Into<String>) -> Self {
+        AsmError {
+            file: file.to_string(),
+            line,
+            column,
+            len: len.max(1),
+            snippet: snippet.to_string(),
+            message: message.into(),
+            notes: Vec::new(),
+        }
+    }
+
+    fn write_with_label(&self, f: &mut fmt::Formatter<'_>, label: &str) -> fmt::Result {
+        // Errors about a whole file, such as failing to read it, have no line
+        if self.line == 0 {
+            writeln!(f, "{}: {}", label, self.message)?;
+            return write!(f, " --> {}", self.file);
+        }
+
+        let line_no = self.line.to_string();
+        let gutter = " ".repeat(line_no.len());
+
+        // Expand tabs so the caret line lines up with the snippet
+        let prefix: String = self.snippet.chars().take(self.column - 1).collect();
+        let offset = prefix.replace('\t', "    ").chars().count();
+        let snippet = self.snippet.trim_end().replace('\t', "    ");
+
+        writeln!(f, "{}: {}", label, self.message)?;
+        writeln!(f, "{}--> {}:{}:{}", gutter, self.file, self.line, self.column)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", line_no, snippet)?;
+        write!(f, "{} | {}{}", gutter, " ".repeat(offset), "^".repeat(self.len))
+    }
+
+    /// Write the diagnostic and its notes, starting with `label` instead of
+    /// `error`, as lints do.
+    pub(crate) fn write_labelled(&self, f: &mut fmt::Formatter<'_>, label: &str) -> fmt::Result {
+        self.write_with_label(f, label)?;
+        for note in &self.notes {
+            writeln!(f)?;
+            note.write_with_label(f, "note")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_labelled(f, "error")
+    }
+}
+
+impl Error for AsmError {}
+
+/// Every diagnostic collected while assembling a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmErrors(pub Vec<AsmError>);
+
+impl AsmErrors {
+    pub fn errors(&self) -> &[AsmError] {
+        &self.0
+    }
+
+    /// The closing line printed after the errors, as rustc does.
+    pub fn summary(&self) -> String {
+        match self.0.len() {
+            1 => "error: aborting due to 1 previous error".to_string(),
+            count => format!("error: aborting due to {} previous errors", count),
+        }
+    }
+}
+
+impl fmt::Display for AsmErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+                writeln!(f)?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for AsmErrors {}
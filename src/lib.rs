@@ -8,4 +8,5 @@ macro_rules! err {
 pub mod assembler;
 pub mod state;
 pub mod simulator;
+pub mod disassembler;
 pub mod error; 
\ No newline at end of file
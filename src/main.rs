@@ -2,42 +2,16 @@ use std::error::Error;
 use std::fs;
 use std::env;
 use verilog_ctf::simulator::{run_program, MEM_SIZE};
-use verilog_ctf::assembler::assemble;
-use reqwest;
+use verilog_ctf::assembler::{assemble_at, assemble_source, AssemblerOptions};
 use serde_json::json;
 
+#[cfg(test)]
 mod tests;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let program = fs::read_to_string("programs/nand_checker.asm")?;
 
-    let prog2 = "\
-    LOADW r7 0xf000
-    LOADW r6 0xf000
-    LOADW r0 0x6F73 ; 'os'
-    LOADW r1 0x6563 ; 'ec'
-    LOADW r2 0x2E69 ; '.i'
-    LOADW r3 0x6F00 ; 'o\0'
-    ADD r7 r7
-    ADD r7 r7
-    ADD r7 r7
-    ADD r7 r6
-
-    ADD r0 r7
-    ADD r1 r7
-    ADD r2 r7
-    ADD r3 r7
-
-    ADD r6 r6
-    ADD r6 r6
-
-    ADD r2 r6
-    FLAG
-
-    HLT
-";
-
     let mut mem = [0u8; MEM_SIZE];
     mem[0x2002] = 0xff;
     mem[0x2004] = 0xff;
@@ -45,23 +19,25 @@ async fn main() -> Result<(), Box<dyn Error>> {
     const HALF: u16 = 0x800;
 
     let mut addr = 0x3000;
-    let mut idx = 0;
 
     let mut circuit_base = Vec::new();
 
-    let assembly = assemble(prog2)?;
-    let writes: Vec<(u16, u16)> = assembly.iter().enumerate()
-        .map(|(i, &value)| (i as u16 + 0x5c / 2 - 8, value))
+    // The payload overwrites the checker from its `end` label onwards
+    let checker = assemble_source(&program, "programs/nand_checker.asm", &AssemblerOptions::default())?;
+    let end = checker.symbols.iter().find(|symbol| symbol.name == "end").ok_or("checker has no `end` label")?;
+    let payload = assemble_at(&fs::read_to_string("programs/payload.asm")?, end.value as usize)?;
+
+    // The circuit writes whole words, so patches are addressed by word
+    let writes: Vec<(u16, u16)> = payload.patches().into_iter()
+        .map(|(addr, value)| ((addr / 2) as u16, value))
         .collect();
 
-    for (a, b) in writes {
-        let base = 12 * idx + HALF;
+    for (idx, (a, b)) in writes.into_iter().enumerate() {
+        let base = 12 * idx as u16 + HALF;
         circuit_base.push((base + 6, base + 7, base + 5));
         circuit_base.push((base + 9, base + 10, 1));
         circuit_base.push((0xfff, 0xfff & !(a), 1));
         circuit_base.push((0xfff, 0xfff & !(b), 1));
-
-        idx += 1;
     }
 
     for (a, b, c) in circuit_base.clone() {
@@ -92,7 +68,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
 
     println!("Assembly bytes:");
-    for (i, byte) in assembly.iter().enumerate() {
+    for byte in payload.image().1 {
         print!("{:04x}", byte);
         print!(" ");
     }
@@ -39,6 +39,15 @@ pub fn get_single_bit_from_json(json: &Value, signal_name: &str) -> Result<i32,
     Ok(bits[0])
 }
 
+/// Where a program stopped and what its registers held then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Halt {
+    /// Whether the CPU halted, rather than running out of cycles
+    pub halted: bool,
+    pub program_counter: u16,
+    pub registers: [u16; 8],
+}
+
 pub fn run_test_program_with_expectations(
     program: &str,
     cycles: usize,
@@ -46,6 +55,16 @@ pub fn run_test_program_with_expectations(
     expected_memory: Option<&[(usize, u8)]>,
     mem: &mut [u8; MEM_SIZE],
 ) -> Result<(), Box<dyn Error>> {
+    simulate(program, cycles, expected_states, expected_memory, mem).map(|_| ())
+}
+
+fn simulate(
+    program: &str,
+    cycles: usize,
+    expected_states: Option<&[(usize, &[i32; 4])]>,
+    expected_memory: Option<&[(usize, u8)]>,
+    mem: &mut [u8; MEM_SIZE],
+) -> Result<Halt, Box<dyn Error>> {
     let instructions = assemble(program)?;
     
     let mut data = [0; 100000];
@@ -83,6 +102,10 @@ pub fn run_test_program_with_expectations(
         get_bits_from_json(&json, "registers[1]")?,
         get_bits_from_json(&json, "registers[2]")?,
         get_bits_from_json(&json, "registers[3]")?,
+        get_bits_from_json(&json, "registers[4]")?,
+        get_bits_from_json(&json, "registers[5]")?,
+        get_bits_from_json(&json, "registers[6]")?,
+        get_bits_from_json(&json, "registers[7]")?,
     ];
 
     state.tick()?;
@@ -92,6 +115,7 @@ pub fn run_test_program_with_expectations(
     state.tick()?;
 
     let mut first = true;
+    let mut halted = false;
 
     for _ in 0..cycles {
         state.flip(clk)?;
@@ -101,7 +125,7 @@ pub fn run_test_program_with_expectations(
         let program_counter = state.get(program_counter_bits.iter())?;
             
         let mut reg_values = [0i32; 4];
-        for (i, reg_bits) in registers.iter().enumerate() {
+        for (i, reg_bits) in registers.iter().take(4).enumerate() {
             reg_values[i] = i32::try_from(state.get(reg_bits.iter())?).unwrap();
         }
         if current_state == 0 && state.data[usize::try_from(clk).unwrap()] == 0 {
@@ -147,6 +171,7 @@ pub fn run_test_program_with_expectations(
 
         if state.data[usize::try_from(halted_bit).unwrap()] == 255 {
             println!("HALTED");
+            halted = true;
             break;
         }
 
@@ -167,7 +192,15 @@ pub fn run_test_program_with_expectations(
 
     println!("Total updates: {}", state.total_updates);
 
-    Ok(())
+    let mut halt = Halt {
+        halted,
+        program_counter: u16::try_from(state.get(program_counter_bits.iter())?)?,
+        registers: [0; 8],
+    };
+    for (value, reg_bits) in halt.registers.iter_mut().zip(registers.iter()) {
+        *value = u16::try_from(state.get(reg_bits.iter())?)?;
+    }
+    Ok(halt)
 }
 
 pub fn run_test_program(
@@ -196,3 +229,8 @@ pub fn run_program(program: &str, cycles: usize, mem: &mut [u8; MEM_SIZE]) -> Re
     
     run_test_program_with_expectations(program, cycles, None, None, mem)
 }
+
+/// Run `program` like [`run_program`], returning where it stopped.
+pub fn run_program_to_halt(program: &str, cycles: usize, mem: &mut [u8; MEM_SIZE]) -> Result<Halt, Box<dyn Error>> {
+    simulate(program, cycles, None, None, mem)
+}
@@ -1,6 +1,5 @@
 use std::error::Error;
 use verilog_macro::synth_cpu;
-use crate::err;
 
 pub struct State<'a> {
     pub data: &'a mut [u8],
@@ -69,7 +68,7 @@ impl State<'_> {
             let nxt = (self.data[i] >> 7) as u64;
             assert!(nxt == 0 || nxt == 1);
 
-            ret = ret | (nxt << cnt);
+            ret |= nxt << cnt;
         }
         Ok(ret)
     }
@@ -1,5 +1,14 @@
 use std::error::Error;
-use verilog_ctf::simulator::{run_test_program, run_test_program_with_memory};
+use std::fs;
+use std::path::PathBuf;
+use verilog_ctf::simulator::{run_program, run_program_to_halt, run_test_program, run_test_program_with_memory, MEM_SIZE};
+use verilog_ctf::assembler::{
+    assemble, assemble_at, assemble_file, assemble_file_with, assemble_object, assemble_source, encode, format, format_program, link, lint,
+    optimize, parse, parse_named, AssemblerOptions, OperandKind, Cfg, Edge, EdgeKind, Instruction, Layout, Lint, LintConfig, Object, OutputFormat, ProgramBuilder,
+    Segment, Severity, Span, StatementKind, Symbol, SymbolKind, CYCLES_PER_INSTRUCTION,
+};
+use verilog_ctf::error::AsmErrors;
+use verilog_ctf::disassembler::{disassemble, is_undecodable, Decoded};
 
 #[test]
 fn test_nop() -> Result<(), Box<dyn Error>> {
@@ -446,3 +455,1382 @@ fn test_flag() -> Result<(), Box<dyn Error>> {
         (0x100, 42)    // Memory at address 0x100 should be 42
     ])
 }
+
+#[test]
+fn test_disassemble_roundtrip() -> Result<(), Box<dyn Error>> {
+    let program = std::fs::read_to_string("programs/nand_checker.asm")?;
+    let words = assemble(&program)?;
+
+    let source: String = disassemble(&words)
+        .iter()
+        .map(|(_, inst)| format!("{}\n", inst))
+        .collect();
+
+    assert_eq!(assemble(&source)?, words);
+    Ok(())
+}
+
+#[test]
+fn test_disassemble_forms() {
+    let words = [0x005d, 0x3000, 0x2107, 0x1232, 0x0a0c, 0x000f];
+
+    assert_eq!(disassemble(&words), vec![
+        (0, Decoded::Instruction(Instruction::LoadW { dest: 5, imm: 0x3000 })),
+        (4, Decoded::Instruction(Instruction::Gt { dest: 0, src1: 1, src2: 2 })),
+        (6, Decoded::Word(0x1232)),
+        (8, Decoded::Instruction(Instruction::Jz { reg: 0, addr: 0x0a })),
+        (10, Decoded::Instruction(Instruction::Invalid)),
+    ]);
+    assert!(is_undecodable(0x1232));
+    assert!(!is_undecodable(0x000f));
+
+    // A LOADW cut off by the end of the image stays one word
+    let words = [0x0108, 0x005d];
+    let decoded = disassemble(&words);
+    assert_eq!(decoded[1], (2, Decoded::Word(0x005d)));
+    let source: String = decoded.iter().map(|(_, line)| format!("{}\n", line)).collect();
+    assert_eq!(assemble(&source).unwrap(), words);
+}
+
+#[test]
+fn test_disassemble_every_word() -> Result<(), Box<dyn Error>> {
+    // Each value is followed by a HLT, which is the immediate if it is a LOADW
+    let values: Vec<u16> = (0..=u16::MAX).collect();
+    for chunk in values.chunks(0x2000) {
+        let words: Vec<u16> = chunk.iter().flat_map(|&word| [word, 0x000f]).collect();
+        let source: String = disassemble(&words).iter().map(|(_, line)| format!("{}\n", line)).collect();
+        assert_eq!(assemble(&source)?, words);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_assembler_diagnostics() {
+    let test_program = "\
+LOADI r0 42
+ADD r0 r9
+FOO r1
+LOADI r1 300
+ADD r1
+";
+
+    let errors = assemble(test_program).unwrap_err();
+    let found: Vec<_> = errors.errors().iter()
+        .map(|e| (e.line, e.column, e.len))
+        .collect();
+
+    assert_eq!(found, vec![(2, 8, 2), (3, 1, 3), (4, 10, 3), (5, 1, 3)]);
+    assert_eq!(errors.errors()[0].snippet, "ADD r0 r9");
+    assert!(errors.errors()[0].message.contains("r0-r7"));
+}
+
+#[test]
+fn test_far_jump_diagnostic() -> Result<(), Box<dyn Error>> {
+    // `near` lands on 0xfe, the last reachable address, and `far` just past it
+    let mut program = String::from("LOADI r0 0\nJZ r0 near\n");
+    program.push_str(&"NOP\n".repeat(125));
+    program.push_str("near:\nJZ r0 far\nfar:\nHLT\n");
+
+    let errors = assemble(&program).unwrap_err();
+    assert_eq!(errors.errors().len(), 1);
+    assert_eq!(errors.errors()[0].line, 129);
+    assert!(errors.errors()[0].message.contains("0x100"));
+
+    // Near targets keep the plain 8-bit encoding
+    let words = assemble(&program.replace("JZ r0 far", "JZ r0 near"))?;
+    assert_eq!(words[1], 0xfe0c);
+
+    let errors = assemble("start:\n        JZ r0 start-10\n").unwrap_err();
+    assert_eq!(errors.errors()[0].message, "jump target `start-10` evaluates to -10, which is negative");
+    Ok(())
+}
+
+#[test]
+fn test_data_labels() -> Result<(), Box<dyn Error>> {
+    let test_program = "\
+        LOADW r1 table    ; R1 = address of the table
+        LOAD r0 r1        ; R0 = first table entry (address of value)
+        LOAD r2 r0        ; R2 = mem[value]
+
+        .data 0x40
+table:
+        value            ; Label addresses are valid data words
+value:
+        0x1234
+    ";
+
+    let expected_states = [
+        (4, &[0, 0x40, 0, 0]),         // After LOADW
+        (6, &[0x42, 0x40, 0, 0]),      // After first LOAD
+        (8, &[0x42, 0x40, 0x1234, 0]), // After second LOAD
+    ];
+
+    run_test_program(test_program, 500, &expected_states)
+}
+
+#[test]
+fn test_expressions() -> Result<(), Box<dyn Error>> {
+    let test_program = "\
+        .equ BASE 0x1000
+        .equ STRIDE 3 * 2
+        .equ LAST table + (STRIDE << 1)
+        LOADW r5 BASE + 2          ; expected output base
+        LOADW r4 LAST
+        LOADI r0 lo(BASE | 0x34)
+        LOADI r1 hi(~BASE & 0xff00)
+        ADDI r2 STRIDE / 4 - 1
+        JZ r0 end - 2
+end:
+        HLT
+        .data 0x40
+table:
+        table + 6
+    ";
+
+    let expected = "\
+        LOADW r5 0x1002
+        LOADW r4 0x4c
+        LOADI r0 0x34
+        LOADI r1 0xef
+        ADDI r2 0
+        JZ r0 14
+        HLT
+        .data 0x40
+        0x46
+    ";
+
+    assert_eq!(assemble(test_program)?, assemble(expected)?);
+    Ok(())
+}
+
+#[test]
+fn test_expression_range_errors() {
+    let test_program = "\
+.equ BIG 0x100
+LOADI r0 BIG - 1
+LOADI r0 BIG
+LOADW r1 BIG * BIG
+ADDI r2 1 / 0
+LOADI r3 missing + 1
+";
+
+    let errors = assemble(test_program).unwrap_err();
+    let found: Vec<_> = errors.errors().iter()
+        .map(|e| (e.line, e.column))
+        .collect();
+
+    assert_eq!(found, vec![(3, 10), (4, 10), (5, 11), (6, 10)]);
+    assert!(errors.errors()[0].message.contains("8-bit"));
+    assert!(errors.errors()[3].message.contains("undefined symbol `missing`"));
+}
+
+#[test]
+fn test_pseudo_instructions() -> Result<(), Box<dyn Error>> {
+    let test_program = "\
+        .scratch r7
+        LI r0 0x1234       ; LOADW
+        LI r1 0x0f0c       ; LOADW
+        LI r6 0x80         ; LOADI, result base
+
+        MOV r2 r0
+        XOR r2 r1
+        STORE r6 r2
+        ADDI r6 2
+        MOV r2 r0
+        OR r2 r1
+        STORE r6 r2
+        ADDI r6 2
+        MOV r2 r0
+        AND r2 r1
+        STORE r6 r2
+        ADDI r6 2
+        MOV r2 r0
+        SUB r2 r1
+        STORE r6 r2
+        ADDI r6 2
+        MOV r2 r1
+        NEG r2
+        STORE r6 r2
+        ADDI r6 2
+        MOV r2 r1
+        SHL r2 4
+        STORE r6 r2
+        ADDI r6 2
+    ";
+
+    run_test_program_with_memory(test_program, 1000, &[
+        (0x80, 0x38), (0x81, 0x1d),  // 0x1234 ^ 0x0f0c
+        (0x82, 0x3c), (0x83, 0x1f),  // 0x1234 | 0x0f0c
+        (0x84, 0x04), (0x85, 0x02),  // 0x1234 & 0x0f0c
+        (0x86, 0x28), (0x87, 0x03),  // 0x1234 - 0x0f0c
+        (0x88, 0xf4), (0x89, 0xf0),  // -0x0f0c
+        (0x8a, 0xc0), (0x8b, 0xf0),  // 0x0f0c << 4
+    ])
+}
+
+#[test]
+fn test_pseudo_branches() -> Result<(), Box<dyn Error>> {
+    let test_program = "\
+        .scratch r7
+        LI r0 0x1234
+        LI r1 0x0f0c
+        LI r6 0x80
+        LI r3 0            ; Counts the branches that went the right way
+        JGT r0 r1 gt_ok
+        HLT
+gt_ok:
+        ADDI r3 1
+        JLT r0 r1 bad
+        JGE r0 r0 ge_ok
+        HLT
+ge_ok:
+        ADDI r3 1
+        JLE r1 r0 le_ok
+        HLT
+le_ok:
+        ADDI r3 1
+        JEQ r0 r1 bad
+        JNE r0 r1 ne_ok
+        HLT
+ne_ok:
+        ADDI r3 1
+        JNZ r3 nz_ok
+        HLT
+nz_ok:
+        ADDI r3 1
+        JMP done
+bad:
+        HLT
+done:
+        STORE r6 r3
+        HLT
+    ";
+
+    run_test_program_with_memory(test_program, 1000, &[(0x80, 5)])
+}
+
+#[test]
+fn test_pseudo_sizing() -> Result<(), Box<dyn Error>> {
+    // `LI` with a forward reference keeps the long form it was sized with
+    let words = assemble("\
+        .scratch r7
+        LI r0 later
+        JMP later
+later:
+        LI r1 later
+    ")?;
+    assert_eq!(words, assemble("\
+        LOADW r0 8
+        LOADI r7 0
+        JZ r7 8
+        LOADI r1 8
+    ")?);
+
+    let errors = assemble("JMP somewhere\nsomewhere:\n").unwrap_err();
+    assert!(errors.errors()[0].message.contains(".scratch"));
+    Ok(())
+}
+
+#[test]
+fn test_macros() -> Result<(), Box<dyn Error>> {
+    let test_program = "\
+.macro load_next dest, ptr, step=2
+        LOAD \\dest \\ptr
+        ADDI \\ptr \\step
+.endm
+
+.macro bump_nonzero reg
+        JZ \\reg skip\\@
+        ADDI \\reg 1
+skip\\@:
+.endm
+
+.macro store_next reg
+        STORE r5 \\reg
+        ADDI r5 2
+.endm
+
+        LOADI r4 0x40
+        LOADI r5 0x80
+        load_next r0, r4
+        load_next r1 r4
+        load_next r2 r4 step=4
+        load_next dest=r3 ptr=r4
+        bump_nonzero r0
+        bump_nonzero r1
+        store_next r0
+        store_next r1
+        store_next r2
+        store_next r3
+        store_next r4
+
+        .data 0x40
+        0
+        5
+        7
+        0
+        9
+    ";
+
+    run_test_program_with_memory(test_program, 1000, &[
+        (0x80, 0),     // r0 was zero, so the increment was skipped
+        (0x82, 6),     // r1 = 5 + 1
+        (0x84, 7),     // r2
+        (0x86, 9),     // r3 read after stepping over the 0 at 0x46
+        (0x88, 0x4a),  // r4 = 0x40 + 2 + 2 + 4 + 2
+    ])
+}
+
+#[test]
+fn test_macro_diagnostics() {
+    let test_program = "\
+.macro set_reg reg, value
+        LOADI \\reg \\value
+.endm
+        set_reg r1, 300
+        set_reg r1
+";
+
+    let errors = assemble(test_program).unwrap_err();
+    let errors = errors.errors();
+    assert_eq!(errors.len(), 2);
+
+    // Missing arguments are reported at the call site
+    assert_eq!(errors[0].line, 5);
+    assert!(errors[0].message.contains("missing argument `value`"));
+
+    // Errors inside the body point at the body line and note the call site
+    assert_eq!(errors[1].line, 2);
+    assert_eq!(errors[1].snippet.trim(), "LOADI r1 300");
+    assert_eq!(errors[1].notes.len(), 1);
+    assert_eq!(errors[1].notes[0].line, 4);
+}
+
+#[test]
+fn test_macro_quoted_arguments() -> Result<(), Box<dyn Error>> {
+    // Escapes in quoted literals are not parameters, and commas or spaces in
+    // a quoted argument do not split it
+    let test_program = "\
+.macro line str, end='\\n'
+        .ascii \\str
+        .byte \\end, '\\0'
+.endm
+        HLT
+        line \"a,b c\"
+        line \"x y\" '!'
+";
+    assert_eq!(assemble(test_program)?, vec![0x000f, 0x2c61, 0x2062, 0x0a63, 0x7800, 0x7920, 0x0021]);
+    Ok(())
+}
+
+/// A fresh scratch directory for tests that assemble files from disk.
+fn test_dir(name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let dir = std::env::temp_dir().join(format!("verilog_ctf_{}_{}", name, std::process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(dir.join("lib"))?;
+    fs::create_dir_all(dir.join("src"))?;
+    Ok(dir)
+}
+
+#[test]
+fn test_include() -> Result<(), Box<dyn Error>> {
+    let dir = test_dir("include")?;
+    fs::write(dir.join("src/main.asm"), "\
+        .include \"consts.asm\"
+        .include \"util.asm\"
+        LOADI r0 VALUE
+        inc r0
+        HLT
+")?;
+    fs::write(dir.join("src/consts.asm"), ".equ VALUE 41\n")?;
+    fs::write(dir.join("lib/util.asm"), ".macro inc reg\n        ADDI \\reg 1\n.endm\n")?;
+
+    // util.asm is only found through the search path
+    let errors = assemble_file(dir.join("src/main.asm")).unwrap_err();
+    assert!(errors.errors()[0].message.contains("cannot find included file `util.asm`"));
+
+    let options = AssemblerOptions { include_paths: vec![dir.join("lib")], ..Default::default() };
+    let assembly = assemble_file_with(dir.join("src/main.asm"), &options)?;
+    assert_eq!(assembly.words, assemble("LOADI r0 41\nADDI r0 1\nHLT")?);
+
+    // Errors name the file the line came from
+    fs::write(dir.join("src/consts.asm"), ".equ VALUE 41\nBOGUS r0\n")?;
+    let errors = assemble_file_with(dir.join("src/main.asm"), &options).unwrap_err();
+    assert_eq!(errors.errors().len(), 1);
+    assert!(errors.errors()[0].file.ends_with("consts.asm"));
+    assert_eq!(errors.errors()[0].line, 2);
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_include_cycle() -> Result<(), Box<dyn Error>> {
+    let dir = test_dir("include_cycle")?;
+    fs::write(dir.join("src/a.asm"), ".include \"b.asm\"\nHLT\n")?;
+    fs::write(dir.join("src/b.asm"), ".include \"a.asm\"\n")?;
+
+    let errors = assemble_file(dir.join("src/a.asm")).unwrap_err();
+    assert_eq!(errors.errors().len(), 1);
+    let error = &errors.errors()[0];
+    assert!(error.file.ends_with("b.asm"));
+    assert!(error.message.starts_with("include cycle:"));
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_listing_and_symbols() -> Result<(), Box<dyn Error>> {
+    let test_program = "\
+.equ COUNT 3
+.data 0x1000
+table:
+        0x1234
+.text
+start:
+        LOADW r1 table
+        LOADI r0 COUNT
+        HLT
+";
+
+    let assembly = assemble_source(test_program, "listing.asm", &AssemblerOptions::default())?;
+    assert_eq!(assembly.words, assemble(test_program)?);
+
+    let rows: Vec<_> = assembly.listing.iter()
+        .map(|line| (line.line, line.address, line.words()))
+        .collect();
+    assert_eq!(rows, vec![
+        (1, None, vec![]),
+        (2, None, vec![]),
+        (3, Some(0x1000), vec![]),
+        (4, Some(0x1000), vec![0x1234]),
+        (5, None, vec![]),
+        (6, Some(0), vec![]),
+        (7, Some(0), vec![0x001d, 0x1000]),
+        (8, Some(4), vec![0x0308]),
+        (9, Some(6), vec![0x000f]),
+    ]);
+    assert!(assembly.listing_text().lines().nth(6).unwrap().starts_with("0000  001d 1000      listing.asm:7"));
+
+    let symbol = |name: &str, value, kind| Symbol { name: name.to_string(), value, kind };
+    assert_eq!(assembly.symbols, vec![
+        symbol("start", 0, SymbolKind::Label),
+        symbol("COUNT", 3, SymbolKind::Constant),
+        symbol("table", 0x1000, SymbolKind::Data),
+    ]);
+    Ok(())
+}
+
+#[test]
+fn test_output_formats() -> Result<(), Box<dyn Error>> {
+    let test_program = "\
+.data 0x3000
+table:
+        0x1234
+        0xabcd
+.text
+        LOADW r1 table
+        HLT
+";
+
+    let assembly = assemble_source(test_program, "formats.asm", &AssemblerOptions::default())?;
+    assert_eq!(assembly.segments, vec![
+        Segment { address: 0, words: vec![0x001d, 0x3000, 0x000f] },
+        Segment { address: 0x3000, words: vec![0x1234, 0xabcd] },
+    ]);
+
+    // Only the binary is padded, and only up to the last word
+    assert_eq!(assembly.render(OutputFormat::Binary).len(), 0x3004);
+
+    let hex = String::from_utf8(assembly.render(OutputFormat::IntelHex))?;
+    assert_eq!(hex, ":060000001D0000300F009E\n:043000003412CDAB0E\n:00000001FF\n");
+
+    let memh = String::from_utf8(assembly.render(OutputFormat::ReadMemH))?;
+    assert_eq!(memh, "@0000\n001d 3000 000f\n@1800\n1234 abcd\n");
+
+    let json: serde_json::Value = serde_json::from_slice(&assembly.render(OutputFormat::Json))?;
+    assert_eq!(json["segments"][1]["address"], 0x3000);
+    assert_eq!(json["segments"][1]["words"][1], 0xabcd);
+    assert_eq!(json["symbols"]["table"], 0x3000);
+
+    let rust = String::from_utf8(assembly.render(OutputFormat::Rust))?;
+    assert!(rust.contains("(0x3000, &[\n        0x1234, 0xabcd,\n    ]),"));
+
+    assert_eq!(OutputFormat::from_extension(std::path::Path::new("out.hex")), Some(OutputFormat::IntelHex));
+    assert_eq!(OutputFormat::from_extension(std::path::Path::new("out.o")), None);
+    Ok(())
+}
+
+const CHALLENGE_LAYOUT: &str = "\
+text            0x0000
+expected_output 0x1000
+circuit         0x3000
+";
+
+#[test]
+fn test_link() -> Result<(), Box<dyn Error>> {
+    let main = "\
+.global start
+start:
+        LOADW r1 table
+        LOAD r0 r1
+        JZ r0 helper
+        HLT
+";
+    let lib = "\
+.global helper, table
+helper:
+        LOADI r2 COUNT
+        HLT
+.equ COUNT 7
+.section circuit
+.data
+table:
+        0x1234
+        start
+";
+
+    let options = AssemblerOptions::default();
+    let main = assemble_object(main, "main.asm", &options)?;
+    let lib = Object::from_json(&assemble_object(lib, "lib.asm", &options)?.to_json())?;
+    let layout = Layout::parse(CHALLENGE_LAYOUT, "challenge.ld")?;
+
+    let linked = link(&[main, lib], &layout)?;
+    assert_eq!(linked.segments, vec![
+        Segment { address: 0, words: vec![0x001d, 0x3000, 0x010b, 0x0a0c, 0x000f, 0x0728, 0x000f] },
+        Segment { address: 0x3000, words: vec![0x1234, 0x0000] },
+    ]);
+    assert!(linked.symbols.contains(&Symbol { name: "table".to_string(), value: 0x3000, kind: SymbolKind::Data }));
+    Ok(())
+}
+
+#[test]
+fn test_link_diagnostics() -> Result<(), Box<dyn Error>> {
+    let options = AssemblerOptions::default();
+    let layout = Layout::parse(CHALLENGE_LAYOUT, "challenge.ld")?;
+
+    // Symbols from other objects must be declared `.global`
+    let a = assemble_object("JZ r0 hidden\n", "a.asm", &options)?;
+    let b = assemble_object("hidden:\nHLT\n", "b.asm", &options)?;
+    let errors = link(&[a, b], &layout).unwrap_err();
+    assert!(errors.errors()[0].message.contains("undefined symbol `hidden`"));
+
+    // A section that grows into the next one is reported where it overlaps
+    let code = assemble_object("LOADI r0 1\nHLT\n", "code.asm", &options)?;
+    let big = assemble_object(".section expected_output\n.data\n0x1\n0x2\n", "big.asm", &options)?;
+    let layout = Layout::parse("text 0\nexpected_output 0x2\n", "small.ld")?;
+    let errors = link(&[code, big], &layout).unwrap_err();
+    assert_eq!(errors.errors().len(), 1);
+    assert_eq!(errors.errors()[0].file, "big.asm");
+    assert!(errors.errors()[0].message.contains("data at address 0x2 overlaps with instructions"));
+
+    // A single file is laid out with the options' layout
+    let options = AssemblerOptions { layout: Layout::parse(CHALLENGE_LAYOUT, "challenge.ld")?, ..Default::default() };
+    let assembly = assemble_source(".section circuit\n.data\ncircuit:\n0x1\n.text\nLOADW r4 circuit\n", "c.asm", &options)?;
+    assert_eq!(assembly.words[1], 0x3000);
+
+    let errors = Layout::parse("text 0x1\nfoo 0x10 0x20\n", "bad.ld").unwrap_err();
+    assert_eq!(errors.errors().len(), 2);
+    Ok(())
+}
+
+#[test]
+fn test_assemble_at() -> Result<(), Box<dyn Error>> {
+    let test_program = "\
+start:
+        LOADI r0 0
+        JZ r0 skip
+        HLT
+.org 0x50
+skip:
+        LOADW r1 start
+";
+
+    // Labels and jump targets resolve against the load address
+    let assembly = assemble_at(test_program, 0x40)?;
+    assert_eq!(assembly.patches(), vec![
+        (0x40, 0x0008),
+        (0x42, 0x500c),
+        (0x44, 0x000f),
+        (0x50, 0x001d),
+        (0x52, 0x0040),
+    ]);
+
+    let (start, image) = assembly.image();
+    assert_eq!(start, 0x40);
+    assert_eq!(image.len(), 10);
+    assert_eq!(&image[3..8], &[0, 0, 0, 0, 0]);
+
+    let errors = assemble_at(test_program, 0x60).unwrap_err();
+    assert!(errors.errors()[0].message.contains("cannot move back from 0x66 to 0x50"));
+    Ok(())
+}
+
+#[test]
+fn test_data_directives() -> Result<(), Box<dyn Error>> {
+    let test_program = "\
+        HLT
+.data 0x1000
+table:
+        .word 1, 0x10, 0b101, 'A', table, -1
+        .byte 1, 2, 3
+        .align 2
+        .ascii \"hi; there\\n\"  ; a comment
+        .asciz \"a,b\"
+        .space 3, 0xff
+        .align 4
+        .fill 2, 0xbeef
+";
+    let assembly = assemble_source(test_program, "data.asm", &AssemblerOptions::default())?;
+    assert_eq!(assembly.segments[1], Segment {
+        address: 0x1000,
+        words: vec![
+            0x0001, 0x0010, 0x0005, 0x0041, 0x1000, 0xffff,
+            0x0201, 0x0003,
+            0x6968, 0x203b, 0x6874, 0x7265, 0x0a65,
+            0x2c61, 0x0062,
+            0xffff, 0x00ff, 0x0000,
+            0xbeef, 0xbeef,
+        ],
+    });
+
+    // A byte left at an odd address keeps words from following it
+    let errors = assemble(".data 0x1000\n.byte 1\n.word 2\n").unwrap_err();
+    assert!(errors.errors()[0].message.contains("`.word` must start at an even address"));
+
+    let errors = assemble(".data 0x1000\n.byte 256\n.ascii \"\\q\"\n.space n\n").unwrap_err();
+    let messages: Vec<&str> = errors.errors().iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(messages, vec![
+        "`256` evaluates to 256, which does not fit in a byte",
+        "unknown escape `\\q`",
+        "`.space` count must be a constant defined before this line",
+    ]);
+    Ok(())
+}
+
+#[test]
+fn test_char_literals() -> Result<(), Box<dyn Error>> {
+    // Pairs pack the first character into the high byte, like FLAG's "os"
+    let words = assemble("LOADW r0 'os'\nLOADW r3 'o\\0'\nLOADI r1 'A'\nLOADI r2 ';' ; comment\n")?;
+    assert_eq!(words, vec![0x000d, 0x6f73, 0x003d, 0x6f00, 0x4118, 0x3b28]);
+
+    // The flag program builds the same words as the hand-packed original
+    let flag = assemble_file("programs/flag.asm")?;
+    let expected = assemble("LOADW r0 0x6F73\nLOADW r1 0x6563\nLOADW r2 0x2E69\nLOADW r3 0x6F00\nFLAG\nHLT\n")?;
+    assert_eq!(flag, expected);
+
+    let errors = assemble("LOADSTR r6 \"abcde\"\nLOADW r0 'abc'\n").unwrap_err();
+    let messages: Vec<&str> = errors.errors().iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(messages, vec![
+        "string needs 3 registers starting at r6, but the last register is r7",
+        "character literal must hold 1 or 2 bytes",
+    ]);
+    Ok(())
+}
+
+#[test]
+fn test_numeric_literals() -> Result<(), Box<dyn Error>> {
+    let words = assemble("\
+        LOADW r0 0b1010_0101
+        LOADW r1 1_000
+        LOADW r2 -2
+        LI r3 -1
+        .data 0x40
+        0x10
+        7
+        -1
+        'ab'
+    ")?;
+    assert_eq!(words, assemble("\
+        LOADW r0 0xa5
+        LOADW r1 0x3e8
+        LOADW r2 0xfffe
+        LOADW r3 0xffff
+        .data 0x40
+        0x10
+        0x7
+        0xffff
+        0x6162
+    ")?);
+
+    // SUBI stands in for ADDI with a negative value, which would add 255
+    run_test_program_with_memory("\
+        LOADI r0 10
+        SUBI r0 3
+        LOADI r1 0x80
+        STORE r1 r0
+        HLT
+    ", 500, &[(0x80, 7), (0x81, 0)])?;
+
+    let errors = assemble("ADDI r0 -1\n.data 0x40\n10\nff\n1_\n").unwrap_err();
+    let messages: Vec<&str> = errors.errors().iter().map(|e| e.message.as_str()).collect();
+    assert!(messages[0].contains("zero-extended"));
+    assert_eq!(&messages[1..], &[
+        "`10` is ambiguous: `.data` used to read bare numbers as hex; write `0x10` for hex, or `.word 10` for decimal",
+        "`ff` is ambiguous: `.data` used to read bare numbers as hex; write `0xff` for hex",
+        "invalid number `1_`",
+    ]);
+    Ok(())
+}
+
+#[test]
+fn test_conditionals() -> Result<(), Box<dyn Error>> {
+    let test_program = "\
+.ifndef VARIANT
+.equ VARIANT 1
+.endif
+.macro load_variant reg
+.if VARIANT == 1
+        LOADI \\reg 0x11
+.elif VARIANT == 2 && !DEBUG
+        LOADI \\reg 0x22
+.else
+        LOADI \\reg 0x33
+.endif
+.endm
+        load_variant r0
+.ifdef DEBUG
+        LOADI r1 VARIANT
+.endif
+        HLT
+";
+
+    let variant = |defines: &[&str]| -> Result<Vec<u16>, Box<dyn Error>> {
+        let mut options = AssemblerOptions::default();
+        for define in defines {
+            options.define(define)?;
+        }
+        Ok(assemble_source(test_program, "variants.asm", &options)?.words)
+    };
+    assert_eq!(variant(&[])?, assemble("LOADI r0 0x11\nHLT")?);
+    assert_eq!(variant(&["VARIANT=2", "DEBUG=0"])?, assemble("LOADI r0 0x22\nLOADI r1 2\nHLT")?);
+    assert_eq!(variant(&["VARIANT=0b10", "DEBUG"])?, assemble("LOADI r0 0x33\nLOADI r1 2\nHLT")?);
+
+    // Includes in skipped blocks are never read
+    let guarded = ".ifdef EXTRA\n.include \"missing.asm\"\n.endif\n        HLT\n";
+    assert_eq!(assemble(guarded)?, vec![0x000f]);
+    let mut options = AssemblerOptions::default();
+    options.define("EXTRA")?;
+    let errors = assemble_source(guarded, "guarded.asm", &options).unwrap_err();
+    assert_eq!(errors.errors()[0].message, "cannot find included file `missing.asm`");
+    let late = ".macro extra\n.equ EXTRA 1\n.endm\n        extra\n.if EXTRA\n.include \"missing.asm\"\n.endif\n";
+    assert!(assemble(late).unwrap_err().errors()[0].message.starts_with("this `.include` was skipped"));
+
+    let mut options = AssemblerOptions::default();
+    assert!(options.define("2FAST").is_err());
+
+    let errors = assemble(".if LATER\n.else\n.else\n.endif\n.endif\n.if 1\n").unwrap_err();
+    let messages: Vec<&str> = errors.errors().iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(messages, vec![
+        "undefined symbol `LATER`; conditions can only use defines and `.equ` constants defined before them",
+        "`.else` after `.else`",
+        "`.endif` without a matching `.if`",
+        "unterminated `.if`, expected `.endif`",
+    ]);
+    Ok(())
+}
+
+#[test]
+fn test_local_labels() -> Result<(), Box<dyn Error>> {
+    // Both routines have their own `.loop`
+    let test_program = "\
+        .scratch r7
+first:
+        LOADI r0 3
+        LOADI r1 0
+.loop:
+        ADDI r1 1
+        SUBI r0 1
+        JNZ r0 .loop
+second:
+        LOADI r0 2
+.loop:
+        ADDI r1 0x10
+        SUBI r0 1
+        JNZ r0 second.loop
+        LOADI r2 0x80
+        STORE r2 r1
+        HLT
+    ";
+    run_test_program_with_memory(test_program, 3000, &[(0x80, 0x23)])?;
+
+    // `1b` is the closest `1:` before, `1f` the closest after
+    let words = assemble("1:\nJZ r0 1b\n1:\nJZ r0 1b\nJZ r0 1f\n1:\nHLT\n")?;
+    assert_eq!(words, assemble("JZ r0 0\nJZ r0 2\nJZ r0 6\nHLT\n")?);
+
+    // Bare values in a `.data` block may refer to local and numeric labels
+    let words = assemble("table:\n        HLT\n.data 0x40\n.entry:\n        .entry\n1:\n        1b\n        .entry + 2\n")?;
+    assert_eq!(words, assemble("        HLT\n.data 0x40\n        0x40\n        0x42\n        0x42\n")?);
+
+    let errors = assemble("\
+start:
+        JZ r0 1f
+start:
+        JZ r0 .missing
+2:
+        JZ r0 3b
+").unwrap_err();
+    let messages: Vec<&str> = errors.errors().iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(messages, vec![
+        "no numeric label `1:` after this line",
+        "`start` is defined more than once",
+        "undefined symbol `.missing`",
+        "no numeric label `3:` before this line",
+    ]);
+    assert_eq!(errors.errors()[1].notes[0].line, 1);
+    assert_eq!(errors.errors()[1].notes[0].message, "first defined here");
+
+    let errors = assemble(".early:\n").unwrap_err();
+    assert!(errors.errors()[0].message.contains("before any global label"));
+    Ok(())
+}
+
+#[test]
+fn test_operand_syntax() -> Result<(), Box<dyn Error>> {
+    let words = assemble("\
+.alias sp r7
+.alias top sp
+        LOADW sp, 0x80      # stack pointer
+        STORE top, r0       ; comment
+        ADD r0,r1
+        GT r2, r3, r4
+.data 0x40
+        .ascii \"a;b#c, d\"
+        .byte ';', '#', ','
+")?;
+    assert_eq!(words, assemble("\
+        LOADW r7 0x80
+        STORE r7 r0
+        ADD r0 r1
+        GT r2 r3 r4
+.data 0x40
+        0x3b61
+        0x2362
+        0x2c63
+        0x6420
+        0x233b
+        0x2c
+")?);
+
+    let errors = assemble(".alias r1 r2\n.alias acc q\nADD acc r0\n").unwrap_err();
+    let messages: Vec<&str> = errors.errors().iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(messages, vec![
+        "invalid alias name `r1`",
+        "expected a register (r0-r7), found `q`",
+        "expected a register (r0-r7), found `acc`",
+    ]);
+    Ok(())
+}
+
+#[test]
+fn test_parse() -> Result<(), Box<dyn Error>> {
+    let source = "\
+start:
+        LOADI r0, 1 ; one
+        ADDI r0 (x + 1) r1
+.data 0x40
+        x + 2
+";
+    let program = parse(source);
+    let kinds: Vec<&StatementKind> = program.statements.iter().map(|s| &s.kind).collect();
+    assert!(matches!(kinds[0], StatementKind::Label(name) if name.text == "start"));
+
+    let StatementKind::Instruction { mnemonic, operands } = kinds[1] else { panic!("expected an instruction") };
+    assert_eq!(mnemonic.text, "LOADI");
+    assert_eq!(operands.iter().map(|o| o.to_string()).collect::<Vec<_>>(), vec!["r0", "1"]);
+    assert!(program.statements[1].commas);
+    let comment = program.statements[1].comment.as_ref().unwrap();
+    assert_eq!((comment.text.as_str(), comment.span), ("; one", Span { line: 2, column: 21, len: 5 }));
+
+    let StatementKind::Instruction { operands, .. } = kinds[2] else { panic!("expected an instruction") };
+    let operands: Vec<&OperandKind> = operands.iter().map(|o| &o.kind).collect();
+    assert_eq!(operands, vec![
+        &OperandKind::Register(0),
+        &OperandKind::Expression("(x + 1)".to_string()),
+        &OperandKind::Register(1),
+    ]);
+    assert!(matches!(kinds[3], StatementKind::Directive { name, .. } if name.text == ".data"));
+    assert!(matches!(kinds[4], StatementKind::Data(value) if value.kind == OperandKind::Expression("x + 2".to_string())));
+
+    let source = "x:\n        LOADI r0 x\n        HLT\n";
+    assert_eq!(encode(&parse(source), &AssemblerOptions::default())?.words, assemble(source)?);
+
+    // Changes to the tree are what gets encoded
+    let mut program = parse(source);
+    let StatementKind::Instruction { operands, .. } = &mut program.statements[1].kind else { panic!("expected an instruction") };
+    assert_eq!(operands[1].kind, OperandKind::Symbol("x".to_string()));
+    operands[0].kind = OperandKind::Register(3);
+    operands[1].kind = OperandKind::Expression("x + 7".to_string());
+    program.statements.remove(2);
+    assert_eq!(program.statements[1].to_source(), "        LOADI r3 x + 7");
+    assert_eq!(encode(&program, &AssemblerOptions::default())?.words, assemble("LOADI r3 7")?);
+    Ok(())
+}
+
+#[test]
+fn test_program_builder() -> Result<(), Box<dyn Error>> {
+    let mut builder = ProgramBuilder::new();
+    let loop_start = builder.named_label("loop");
+    let done = builder.label();
+    let table = builder.label();
+    builder.loadw(1, table).loadi(0, 3);
+    builder.bind(loop_start).jz(0, done).addi(1, 2).add(0, 0).jz(2, loop_start);
+    builder.bind(done).instruction(Instruction::Gt { dest: 3, src1: 1, src2: 0 }).hlt();
+    builder.bind(table).words([0x1234, -1]).bytes(b"ab");
+
+    assert_eq!(builder.finish()?, assemble("\
+        LOADW r1 table
+        LOADI r0 3
+loop:
+        JZ r0 done
+        ADDI r1 2
+        ADD r0 r0
+        JZ r2 loop
+done:
+        GT r3 r1 r0
+        HLT
+table:
+        .word 0x1234, 0xffff
+        .byte 'a', 'b'
+")?);
+    assert_eq!(builder.finish()?, assemble(&builder.source())?);
+
+    let mut builder = ProgramBuilder::new();
+    let missing = builder.label();
+    builder.jz(0, missing).add(8, 0);
+    let errors = builder.finish().unwrap_err();
+    let messages: Vec<&str> = errors.errors().iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(messages, vec!["undefined symbol `__label_0`", "register index out of range: `r8` (expected r0-r7)"]);
+    let places: Vec<(usize, usize)> = errors.errors().iter().map(|e| (e.line, e.column)).collect();
+    assert_eq!(places, vec![(1, 15), (2, 13)]);
+
+    // Values that cannot fit are reported where they are added
+    let mut builder = ProgramBuilder::new();
+    builder.nop().jz(0, -1).loadw(1, 0x10000).words([1, -0x8001]);
+    let errors = builder.finish().unwrap_err();
+    let reported: Vec<(usize, &str)> = errors.errors().iter().map(|e| (e.line, e.message.as_str())).collect();
+    assert_eq!(reported, vec![
+        (2, "jump target -1 is outside 0x00-0xff, the addresses JZ can reach"),
+        (3, "65536 does not fit in 16 bits"),
+        (4, "-32769 does not fit in 16 bits"),
+    ]);
+
+    // Bad names and labels from another builder are reported, not panics
+    let mut other = ProgramBuilder::new();
+    let foreign = other.label();
+    let mut builder = ProgramBuilder::new();
+    let spaced = builder.named_label("two words");
+    let reserved = builder.named_label("__label_1");
+    let local = builder.named_label(".loop");
+    builder.bind(spaced).bind(reserved).jz(0, foreign).bind(foreign).hlt().jz(0, local).bind(local);
+    assert_eq!(builder.source().lines().take(2).collect::<Vec<_>>(), vec!["__label_0:", "__label_1:"]);
+    let errors = builder.finish().unwrap_err();
+    let reported: Vec<(usize, &str)> = errors.errors().iter().map(|e| (e.line, e.message.as_str())).collect();
+    assert_eq!(reported, vec![
+        (1, "invalid label name `two words`"),
+        (2, "invalid label name `__label_1`"),
+        (3, "label was created by a different `ProgramBuilder`"),
+        (4, "label was created by a different `ProgramBuilder`"),
+        (5, "invalid label name `.loop`"),
+    ]);
+    Ok(())
+}
+
+#[test]
+fn test_format() -> Result<(), Box<dyn Error>> {
+    let source = "\
+.macro\tapply op, a, b
+  \\op \\a,\t\\b   ; apply
+.endm
+start:     ; entry
+\tloadi r0 ( 1 +\t2 )
+   ; step
+  apply add, r0, r0
+    .ascii \"a  b\"
+  ;done
+\thlt
+";
+    let formatted = format(source);
+    assert_eq!(formatted, "\
+.macro apply op, a, b
+        \\op \\a, \\b              ; apply
+.endm
+start:                          ; entry
+        LOADI r0 ( 1 + 2 )
+        ; step
+        APPLY add, r0, r0
+        .ascii \"a  b\"
+        ;done
+        HLT
+");
+    assert_eq!(format(&formatted), formatted);
+    assert_eq!(assemble(&formatted)?, assemble(source)?);
+    for path in ["programs/nand_checker.asm", "programs/flag.asm", "programs/payload.asm"] {
+        let source = fs::read_to_string(path)?;
+        assert_eq!(format(&source), source, "{} is not formatted", path);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_lint() -> Result<(), Box<dyn Error>> {
+    let lints = |source: &str, config: &LintConfig| -> Result<Vec<(Lint, Severity, usize)>, AsmErrors> {
+        let diagnostics = lint(&parse(source), &AssemblerOptions::default(), config)?;
+        Ok(diagnostics.iter().map(|d| (d.lint, d.severity, d.error.line)).collect())
+    };
+    let source = "\
+.scratch r7
+        LOADW r1 buf
+        LOADI r2 5
+        STORE r2 r1
+        ADD r2 r3
+        JZ r2 odd + 1
+odd:
+        JMP done
+        NOP
+done:
+        ADDI r1 1
+.data 0x1000
+buf:
+        0x10
+";
+    assert_eq!(lints(source, &LintConfig::default())?, vec![
+        (Lint::StoreOrder, Severity::Warn, 4),
+        (Lint::UninitializedRegister, Severity::Warn, 5),
+        (Lint::OddJump, Severity::Deny, 6),
+        (Lint::Unreachable, Severity::Warn, 9),
+        (Lint::MissingHlt, Severity::Warn, 11),
+        (Lint::ReservedOverlap, Severity::Warn, 14),
+    ]);
+
+    let diagnostics = lint(&parse(source), &AssemblerOptions::default(), &LintConfig::default())?;
+    assert_eq!(diagnostics[1].error.message,
+        "r3 is read before anything is written to it; it is 0 after reset, so write 0 explicitly if that is intended");
+    assert_eq!((diagnostics[1].error.column, diagnostics[1].error.len), (16, 2));
+    assert_eq!((diagnostics[2].error.column, diagnostics[2].error.len), (15, 7));
+
+    // Configured for every file, then overridden by the file itself
+    let mut config = LintConfig::default();
+    config.set(Lint::Unreachable, Severity::Allow);
+    config.set(Lint::StoreOrder, Severity::Deny);
+    let configured = format!("; lint: allow reserved-overlap, uninitialized-register\n{}", source);
+    assert_eq!(lints(&configured, &config)?, vec![
+        (Lint::StoreOrder, Severity::Deny, 5),
+        (Lint::OddJump, Severity::Deny, 7),
+        (Lint::MissingHlt, Severity::Warn, 12),
+    ]);
+    let errors = lints("; lint: allow everything\nHLT\n", &config).unwrap_err();
+    assert_eq!(errors.errors()[0].message, "unknown lint `everything`");
+
+    for path in ["programs/nand_checker.asm", "programs/flag.asm"] {
+        let program = parse_named(&fs::read_to_string(path)?, path);
+        assert_eq!(lint(&program, &AssemblerOptions::default(), &LintConfig::default())?, Vec::new());
+    }
+
+    // The CPU starts at address 0, not wherever the code starts
+    let late = ".org 0x10\n        LOADI r0 1\n        HLT\n";
+    assert_eq!(lints(late, &LintConfig::default())?, vec![(Lint::Unreachable, Severity::Warn, 2)]);
+    let diagnostics = lint(&parse(late), &AssemblerOptions::default(), &LintConfig::default())?;
+    assert!(diagnostics[0].error.message.starts_with("execution starts at address 0, where there is no code"));
+    Ok(())
+}
+
+#[test]
+fn test_cfg() -> Result<(), Box<dyn Error>> {
+    let assembly = assemble_file_with("programs/nand_checker.asm", &AssemblerOptions::default())?;
+    let cfg = Cfg::new(&assembly);
+    let block = |name: &str| cfg.blocks.iter().position(|block| block.label.as_deref() == Some(name)).unwrap();
+
+    assert_eq!(cfg.blocks[0].start, 0);
+    assert_eq!(cfg.blocks[block("check_start")].successors, vec![
+        Edge { to: block("start"), kind: EdgeKind::Branch },
+        Edge { to: block("check_start") + 1, kind: EdgeKind::FallThrough },
+    ]);
+    // `JZ r7 lose` is always taken, since r7 is 0 from `end` onwards
+    assert_eq!(cfg.blocks[block("check_nxt") - 1].successors, vec![Edge { to: block("lose"), kind: EdgeKind::Jump }]);
+    assert!(cfg.blocks[block("win")].halts && cfg.blocks[block("lose")].halts);
+    assert!(cfg.blocks.iter().all(|block| block.reachable && block.escapes.is_empty()));
+
+    assert_eq!(cfg.dominators[block("end")], Some(block("check_start")));
+    assert!(cfg.dominates(block("end"), block("win")));
+    assert!(!cfg.dominates(block("start"), block("end")));
+    let headers: Vec<usize> = cfg.loops.iter().map(|l| l.header).collect();
+    assert_eq!(headers, vec![block("check_start"), block("start"), block("finish_start")]);
+
+    // Two cycles per instruction: LOADW r0, LOADW r5, STORE and HLT
+    assert_eq!(cfg.blocks[block("win")].cycles, 4 * CYCLES_PER_INSTRUCTION);
+    assert!(cfg.to_dot().contains(&format!("b{} -> b{} [label=\"jump\"];", block("check_nxt") - 1, block("lose"))));
+    let json: serde_json::Value = serde_json::from_str(&cfg.to_json())?;
+    assert_eq!(json["blocks"][block("win")]["instructions"][0]["text"], "LOADW r0 0x1337");
+
+    let cfg = Cfg::new(&assemble_at(".scratch r7\n        JMP done\n        NOP\ndone:\n        ADDI r0 1\n", 0)?);
+    assert_eq!(cfg.blocks.len(), 3);
+    assert!(!cfg.blocks[1].reachable);
+    assert_eq!(cfg.blocks[2].escapes, vec![8]);
+    Ok(())
+}
+
+#[test]
+fn test_optimize() -> Result<(), Box<dyn Error>> {
+    let source = "\
+.scratch r7
+        LOADI r7 0
+        LOADI r3 1
+        LOADW r1 0x1000
+        ADD r1 r1
+        ADD r1 r1               ; r1 = 0x4000
+        LOAD r2 r1
+        ADD r2 r3
+        ADD r2 r3
+        ADDI r2 3
+        LOADW r5 0x2000
+        STORE r5 r2
+        LOADI r3 1
+        JMP done
+        HLT
+done:
+        LOADW r6 200
+        ADDI r5 2
+        STORE r5 r6
+        HLT
+";
+    let optimization = optimize(&parse(source), &AssemblerOptions::default())?;
+    let rewrites: Vec<(usize, Option<&str>)> =
+        optimization.rewrites.iter().map(|rewrite| (rewrite.line, rewrite.after.as_deref())).collect();
+    assert_eq!(rewrites, vec![
+        (4, Some("LOADW r1 0x4000")),
+        (5, None),
+        (6, None),
+        (8, Some("ADDI r2 5")),
+        (9, None),
+        (10, None),
+        (13, None),
+        (14, Some("JZ r7 done")),
+        (17, Some("LOADI r6 200")),
+    ]);
+    assert_eq!((optimization.bytes_saved, optimization.cycles_saved), (14, 12));
+
+    // Lines keep their numbers and comments
+    let optimized = format_program(&optimization.program);
+    assert_eq!(optimized.lines().count(), source.lines().count());
+    assert_eq!(optimized.lines().nth(5), Some("        ; r1 = 0x4000"));
+
+    // Both versions leave the same values in memory
+    for program in [source, optimized.as_str()] {
+        let mut mem = [0u8; MEM_SIZE];
+        mem[0x4000] = 7;
+        run_program(program, 200, &mut mem)?;
+        assert_eq!(mem[0x2000..0x2004], [12, 0, 200, 0]);
+    }
+
+    // Values placed by the layout are not relied on
+    let optimization = optimize(&parse("        LOADW r1 end\n        LOADW r1 end\nend:\n        HLT\n"), &AssemblerOptions::default())?;
+    assert_eq!(optimization.rewrites.len(), 2);
+    assert!(optimization.rewrites.iter().all(|rewrite| rewrite.reason == "value fits in LOADI"));
+
+    // Lines a fold leaves as they were are not reported
+    let optimization = optimize(&parse("        LOADI r1 5\n        LOADI r1 5\n        HLT\n"), &AssemblerOptions::default())?;
+    let rewrites: Vec<(usize, Option<&str>)> =
+        optimization.rewrites.iter().map(|rewrite| (rewrite.line, rewrite.after.as_deref())).collect();
+    assert_eq!(rewrites, vec![(2, None)]);
+
+    // Removing code would move what a numeric jump target or `.org` points at
+    let fixed = "        LOADI r1 5\n        LOADI r1 5\n        JZ r0 8\n        HLT\n        ADDI r2 1\n        HLT\n";
+    let errors = optimize(&parse(fixed), &AssemblerOptions::default()).unwrap_err();
+    assert_eq!(errors.errors()[0].line, 3);
+    assert!(errors.errors()[0].message.starts_with("cannot optimize a jump to the fixed address `8`"));
+    assert!(optimize(&parse(".org 0x10\n        HLT\n"), &AssemblerOptions::default()).is_err());
+
+    // So would an offset from a code label, written out or through `.equ`
+    let offset = "start:\n        LOADI r1 5\n        LOADI r1 5\n        JZ r0 start+4\n        HLT\n";
+    let errors = optimize(&parse(offset), &AssemblerOptions::default()).unwrap_err();
+    assert!(errors.errors()[0].message.starts_with("cannot optimize a jump to `start+4`, which is computed from a label"));
+    let equ = ".equ TGT start + 4\nstart:\n        LOADI r1 5\n        LOADI r1 5\n        JZ r0 TGT\n        HLT\n";
+    let errors = optimize(&parse(equ), &AssemblerOptions::default()).unwrap_err();
+    assert!(errors.errors()[0].message.starts_with("cannot optimize a jump to `TGT`, which is computed from a label"));
+    let load = "start:\n        LOADI r1 5\n        LOADI r1 5\n        LOADW r2 start+4\n        HLT\n";
+    let errors = optimize(&parse(load), &AssemblerOptions::default()).unwrap_err();
+    assert!(errors.errors()[0].message.starts_with("cannot optimize code that uses `start+4`"));
+    Ok(())
+}
+
+#[test]
+fn test_optimize_keeps_behaviour() -> Result<(), Box<dyn Error>> {
+    let source = "\
+.scratch r7
+.equ COUNT 3
+start:
+        LOADI r7 0
+        LOADI r1 COUNT
+        LOADW r2 0x10
+        LOADI r3 0
+.loop:
+        JZ r1 1f
+        ADD r3 r2
+        ADDI r3 1
+        ADDI r3 1
+        SUBI r1 1
+        LOADI r7 0
+        JMP .loop
+1:
+        LOADW r4 start
+        LOADW r5 buffer
+        LOAD r5 r5
+        LOADI r6 9
+        LOADI r6 9
+        JZ r0 done
+        HLT
+done:
+        HLT
+buffer:
+        .word 1
+";
+    let optimization = optimize(&parse(source), &AssemblerOptions::default())?;
+    assert!(optimization.bytes_saved > 0);
+    let optimized = format_program(&optimization.program);
+
+    // Both versions stop on the same line with the same registers, none of
+    // which holds an address that moved
+    let mut halts = Vec::new();
+    for program in [source, optimized.as_str()] {
+        let assembly = encode(&parse(program), &AssemblerOptions::default())?;
+        let mut mem = [0u8; MEM_SIZE];
+        let halt = run_program_to_halt(program, 400, &mut mem)?;
+        assert!(halt.halted);
+        let line = assembly.listing.iter()
+            .find(|line| line.address == Some(usize::from(halt.program_counter) - 2))
+            .map(|line| line.line);
+        halts.push((line, halt.registers));
+    }
+    assert_eq!(halts[0].0, Some(24));
+    assert_eq!(halts[0], halts[1]);
+    Ok(())
+}
+
+#[test]
+fn test_structured_control_flow() -> Result<(), Box<dyn Error>> {
+    let program = "\
+.scratch r7
+main:
+        LOADI r2 0
+.for r0 3
+.for r1 4
+        ADDI r2 1
+.endf
+.endf
+        LOADI r3 5
+.while r3 != 0
+        ADDI r2 2
+        SUBI r3 1
+.endw
+        LOADI r4 0
+.ifz r4
+        ADDI r2 1
+        JZ r4 .after
+.else
+        ADDI r2 100
+.endif
+.after:
+.ifz r2
+        ADDI r2 100
+.endif
+        LOADI r5 10
+.while r5 > r4
+        ADDI r4 1
+.endw
+        ADD r2 r4
+        LOADW r5 0x1000
+        STORE r5 r2
+        HLT
+";
+    // 3 * 4 + 5 * 2 + 1, then the 10 passes of the last loop
+    run_test_program_with_memory(program, 2000, &[(0x1000, 33), (0x1001, 0)])?;
+
+    let listing = assemble_source(program, "loops.asm", &AssemblerOptions::default())?.listing_text();
+    let listed = |location: &str, code: &str| listing.lines().any(|line| line.contains(location) && line.ends_with(code));
+    assert!(listed("loops.asm:13 ", "JMP __while_3 ; scratch r7"));
+    assert!(listed("loops.asm:26 ", "JLE r5 r4 __endw_6 ; scratch r7"));
+
+    // Run-time blocks nest inside assembly-time ones and share their `.endif`
+    let nested = "\
+.if 0
+.ifz r0
+        NOP
+.else
+        NOP
+.endif
+.endif
+        HLT
+";
+    assert_eq!(assemble(nested)?, vec![0x000f]);
+
+    let message = |source: &str| assemble(source).unwrap_err().errors()[0].message.clone();
+    assert_eq!(message(".endw\n"), "`.endw` without a matching `.while`");
+    assert_eq!(message(".scratch r7\n.ifz r0\n.endw\n.endif\n"), "`.endw` inside the `.ifz` on line 2, which must be closed first");
+    assert_eq!(message(".while r0\n.endw\n"), "`.while` expects a condition such as `r0 != 0` or `r0 > r1`");
+    assert_eq!(message(".for r0 2\n"), "unterminated `.for`, expected `.endf`");
+    assert_eq!(message(".ifz r0\n.elif 1\n.endif\n"), "`.elif` cannot follow `.ifz`; nest another `.ifz` instead");
+    assert_eq!(message(".while r0 != 2\n.endw\n"), "`.while` can only compare a register with 0 or with another register");
+    assert_eq!(message(".while r0 < 0\n.endw\n"), "`r0 < 0` is never true, as registers are unsigned");
+    assert_eq!(message(".while r0 >= 0\n.endw\n"), "`r0 >= 0` is always true, as registers are unsigned");
+    assert_eq!(message(".while r0 <> r1\n.endw\n"), "unknown comparison `<>` in `.while`; use `==`, `!=`, `>`, `<`, `>=` or `<=`");
+    assert_eq!(message("__endw_1:\n"), "`__endw_1` is reserved for the labels of `.while`, `.ifz` and `.for`");
+
+    // Any constant expression that is 0 tests the register alone
+    let zero_test = assemble(".scratch r7\n.while r0 != 0\n        SUBI r0 1\n.endw\n")?;
+    assert_eq!(assemble(".scratch r7\n.while r0 != 0x0\n        SUBI r0 1\n.endw\n")?, zero_test);
+    assert_eq!(assemble(".scratch r7\n.equ NONE 1 - 1\n.while r0 != NONE\n        SUBI r0 1\n.endw\n")?, zero_test);
+    assert_eq!(assemble(".scratch r7\n.while r0 > 0\n        SUBI r0 1\n.endw\n")?, zero_test);
+    let until_zero = assemble(".scratch r7\n.while r0 == 0\n        ADDI r0 1\n.endw\n")?;
+    assert_eq!(assemble(".scratch r7\n.while r0 <= 0\n        ADDI r0 1\n.endw\n")?, until_zero);
+    Ok(())
+}
+
+#[test]
+fn test_call_stack() -> Result<(), Box<dyn Error>> {
+    let program = "\
+.scratch r7
+.stack r6 0x0800 0x100
+        LOADI r0 7
+        CALL fib
+        LOADW r2 0x1000
+        STORE r2 r1
+        HLT
+
+; r1 = fib(r0), using r0 and r2
+fib:
+        LOADI r2 2
+        JGT r2 r0 .small
+        PUSH r0
+        SUBI r0 1
+        CALL fib
+        POP r0
+        PUSH r1
+        SUBI r0 2
+        CALL fib
+        POP r2
+        ADD r1 r2
+        RET
+.small:
+        LOADI r1 0
+        ADD r1 r0
+        RET
+";
+    run_test_program_with_memory(program, 12000, &[(0x1000, 13), (0x1001, 0)])?;
+
+    // Code after a `CALL` is reached through the routine's `RET`
+    let assembly = assemble_source(program, "fib.asm", &AssemblerOptions::default())?;
+    let cfg = Cfg::new(&assembly);
+    assert!(cfg.blocks.iter().all(|block| block.reachable));
+    assert!(cfg.blocks[0].successors.iter().any(|edge| edge.kind == EdgeKind::Return));
+
+    let message = |source: &str| assemble(source).unwrap_err().errors()[0].message.clone();
+    assert_eq!(message(".scratch r7\n        CALL f\nf:\n        RET\n"), "`CALL` needs a stack; set one up with `.stack rN BASE SIZE`");
+    assert_eq!(message(".scratch r7\n.stack r7 0x800 0x10\n"), "stack pointer r7 is also the scratch register");
+    assert_eq!(message(".stack r6 0x800 0x10\n        PUSH r6\n"), "stack pointer r6 cannot be an operand of `PUSH`");
+    assert_eq!(message(".stack r6 0x0 0x10\n        HLT\n"), "the stack at 0x0000-0x000f overlaps instructions at 0x0");
+    Ok(())
+}